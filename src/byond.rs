@@ -141,3 +141,43 @@ where
         }
     }
 }
+
+/// A boolean from DM, where `json_encode(TRUE)` gives `1`.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ByondBool {
+    Bool(bool),
+    Number(f64),
+}
+
+#[cfg(feature = "serde")]
+impl From<ByondBool> for bool {
+    fn from(value: ByondBool) -> Self {
+        match value {
+            ByondBool::Bool(value) => value,
+            ByondBool::Number(value) => value != 0.0,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[allow(dead_code)] // Used depending on feature set
+/// For `#[serde(deserialize_with)]` on boolean options, so DM can pass `TRUE`/`FALSE` as well as `true`/`false`.
+pub fn deserialize_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <ByondBool as serde::Deserialize>::deserialize(deserializer).map(Into::into)
+}
+
+#[cfg(feature = "serde")]
+#[allow(dead_code)] // Used depending on feature set
+/// Like `deserialize_bool`, for options where null means unset.
+pub fn deserialize_optional_bool<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <Option<ByondBool> as serde::Deserialize>::deserialize(deserializer)
+        .map(|value| value.map(Into::into))
+}
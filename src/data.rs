@@ -0,0 +1,117 @@
+use crate::error::{respond, Error, Result};
+use ini::Ini;
+use serde_json::{Map, Value};
+
+byond_fn!(fn data_convert(from_format, to_format, text) {
+    respond(data_convert_impl(from_format, to_format, text))
+});
+
+#[derive(Clone, Copy)]
+enum Format {
+    Toml,
+    Yaml,
+    Json,
+    Json5,
+    Ini,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "toml" => Self::Toml,
+            "yaml" | "yml" => Self::Yaml,
+            "json" => Self::Json,
+            "json5" => Self::Json5,
+            "ini" => Self::Ini,
+            _ => return Err(Error::UnknownDataFormat(name.to_owned())),
+        })
+    }
+
+    fn decode(self, text: &str) -> Result<Value> {
+        Ok(match self {
+            Self::Toml => serde_json::to_value(toml_dep::from_str::<toml_dep::Value>(text)?)?,
+            Self::Yaml => serde_yaml::from_str(text)?,
+            Self::Json => serde_json::from_str(text)?,
+            Self::Json5 => json5::from_str(text)?,
+            Self::Ini => ini_to_json(&Ini::load_from_str(text)?),
+        })
+    }
+
+    fn encode(self, value: &Value) -> Result<String> {
+        Ok(match self {
+            Self::Toml => toml_dep::to_string_pretty(&serde_json::from_value::<toml_dep::Value>(
+                value.clone(),
+            )?)?,
+            Self::Yaml => serde_yaml::to_string(value)?,
+            Self::Json => serde_json::to_string(value)?,
+            Self::Json5 => json5::to_string(value)?,
+            Self::Ini => {
+                let mut output = Vec::new();
+                json_to_ini(value)?.write_to(&mut output)?;
+                String::from_utf8_lossy(&output).into_owned()
+            }
+        })
+    }
+}
+
+fn data_convert_impl(from_format: &str, to_format: &str, text: &str) -> Result<String> {
+    let (from, to) = (Format::parse(from_format)?, Format::parse(to_format)?);
+    to.encode(&from.decode(text)?)
+}
+
+/// Keys outside of any section end up at the top level, each section becomes an object.
+/// INI has no types, so every value is a string.
+fn ini_to_json(ini: &Ini) -> Value {
+    let mut root = Map::new();
+    for (section, properties) in ini.iter() {
+        let target = match section {
+            Some(name) => match root
+                .entry(name)
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                Value::Object(map) => map,
+                _ => continue,
+            },
+            None => &mut root,
+        };
+        for (key, value) in properties.iter() {
+            target.insert(key.to_owned(), Value::String(value.to_owned()));
+        }
+    }
+    Value::Object(root)
+}
+
+fn json_to_ini(value: &Value) -> Result<Ini> {
+    let Value::Object(root) = value else {
+        return Err(Error::InvalidIniValue(
+            "a value that is not an object".to_owned(),
+        ));
+    };
+
+    let mut ini = Ini::new();
+    for (key, value) in root {
+        match value {
+            Value::Object(section) => {
+                for (name, value) in section {
+                    let value = ini_value(value)
+                        .ok_or_else(|| Error::InvalidIniValue(format!("{key}.{name}")))?;
+                    ini.with_section(Some(key.as_str())).set(name, value);
+                }
+            }
+            value => {
+                let value = ini_value(value).ok_or_else(|| Error::InvalidIniValue(key.clone()))?;
+                ini.with_general_section().set(key, value);
+            }
+        }
+    }
+    Ok(ini)
+}
+
+fn ini_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Null => Some(String::new()),
+        Value::Bool(_) | Value::Number(_) => Some(value.to_string()),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
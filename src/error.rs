@@ -34,7 +34,17 @@ pub enum Error {
     #[cfg(feature = "png")]
     #[error(transparent)]
     ImageEncoding(#[from] EncodingError),
-    #[cfg(any(feature = "http", feature = "iconforge"))]
+    #[cfg(any(
+        feature = "data",
+        feature = "http",
+        feature = "iconforge",
+        feature = "json",
+        feature = "log",
+        feature = "time",
+        feature = "toml",
+        feature = "unzip",
+        feature = "url"
+    ))]
     #[error(transparent)]
     JsonSerialization(#[from] serde_json::Error),
     #[error(transparent)]
@@ -49,15 +59,99 @@ pub enum Error {
     #[cfg(feature = "http")]
     #[error(transparent)]
     Request(#[from] reqwest::Error),
-    #[cfg(feature = "toml")]
+    #[cfg(feature = "http")]
+    #[error("Invalid value for the {0} option.")]
+    InvalidHttpOption(&'static str),
+    #[cfg(feature = "http")]
+    #[error("No HTTP client profile is named {0}.")]
+    UnknownHttpClient(String),
+    #[cfg(feature = "http")]
+    #[error("Response body is over the limit of {0} bytes.")]
+    HttpBodyTooLarge(u64),
+    #[cfg(any(feature = "data", feature = "toml"))]
     #[error(transparent)]
     TomlDeserialization(#[from] toml_dep::de::Error),
-    #[cfg(feature = "toml")]
+    #[cfg(any(feature = "data", feature = "toml"))]
     #[error(transparent)]
     TomlSerialization(#[from] toml_dep::ser::Error),
+    #[cfg(feature = "toml")]
+    #[error(transparent)]
+    TomlEdit(#[from] toml_edit::TomlError),
+    #[cfg(feature = "toml")]
+    #[error(transparent)]
+    TomlEditSerialization(#[from] toml_edit::ser::Error),
+    #[cfg(feature = "toml")]
+    #[error("Cannot use {0} as a key: it is empty or passes through a value that is not a table.")]
+    InvalidTomlKey(String),
+    #[cfg(feature = "data")]
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[cfg(feature = "data")]
+    #[error(transparent)]
+    Json5(#[from] json5::Error),
+    #[cfg(feature = "data")]
+    #[error(transparent)]
+    Ini(#[from] ini::ParseError),
+    #[cfg(feature = "data")]
+    #[error("Unknown data format: {0}")]
+    UnknownDataFormat(String),
+    #[cfg(feature = "data")]
+    #[error("Cannot write {0} as INI: only sections of plain values are supported.")]
+    InvalidIniValue(String),
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    JsonPatch(#[from] json_patch::PatchError),
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    JsonPath(#[from] serde_json_path::ParseError),
+    #[cfg(feature = "json")]
+    #[error("Invalid JSON Schema: {0}")]
+    InvalidJsonSchema(String),
+    #[cfg(feature = "json")]
+    #[error("No JSON Schema is registered as {0}.")]
+    UnknownJsonSchema(String),
     #[cfg(feature = "unzip")]
     #[error(transparent)]
     Unzip(#[from] ZipError),
+    #[cfg(feature = "unzip")]
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[cfg(feature = "unzip")]
+    #[error("Unknown compression format: {0}")]
+    InvalidCompression(String),
+    #[cfg(feature = "unzip")]
+    #[error("Refusing to extract {0}: {1}.")]
+    UnsafeArchiveEntry(String, &'static str),
+    #[cfg(feature = "unzip")]
+    #[error("Archive is over the limit of {0}.")]
+    ArchiveLimit(String),
+    #[cfg(feature = "unzip")]
+    #[error("Archive has sha256 {actual}, expected {expected}.")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[cfg(feature = "url")]
+    #[error(transparent)]
+    UrlParse(#[from] url_dep::ParseError),
+    #[cfg(feature = "url")]
+    #[error("Invalid URL {0}.")]
+    InvalidUrlComponent(&'static str),
+    #[cfg(feature = "log")]
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[cfg(any(feature = "log", feature = "time"))]
+    #[error("Unknown timezone: {0}")]
+    InvalidTimezone(String),
+    #[cfg(any(feature = "log", feature = "time"))]
+    #[error("Invalid time format string.")]
+    InvalidTimeFormat,
+    #[cfg(feature = "time")]
+    #[error("Timestamp is out of range or does not exist in the timezone.")]
+    InvalidTimestamp,
+    #[cfg(feature = "time")]
+    #[error(transparent)]
+    TimeParse(#[from] chrono::ParseError),
+    #[cfg(feature = "time")]
+    #[error(transparent)]
+    Cron(#[from] croner::errors::CronError),
     #[cfg(feature = "hash")]
     #[error("Unable to decode hex value.")]
     HexDecode,
@@ -71,6 +165,27 @@ pub enum Error {
     Panic(String),
 }
 
+/// Wraps a result in the `{"success": ..., "content": ...}` envelope DM expects.
+#[cfg(any(
+    feature = "data",
+    feature = "json",
+    feature = "log",
+    feature = "toml",
+    feature = "unzip",
+    feature = "url"
+))]
+pub fn respond<T: serde::Serialize>(result: Result<T>) -> Option<String> {
+    serde_json::to_string(&match result {
+        Ok(value) => serde_json::json!({
+            "success": true, "content": value
+        }),
+        Err(error) => serde_json::json!({
+            "success": false, "content": error.to_string()
+        }),
+    })
+    .ok()
+}
+
 impl From<Utf8Error> for Error {
     fn from(source: Utf8Error) -> Self {
         Self::Utf8 {
@@ -85,6 +85,10 @@ pub fn string_hash(algorithm: &str, string: &str) -> Result<String> {
     hash_algorithm(algorithm, string)
 }
 
+pub fn bytes_hash(algorithm: &str, bytes: &[u8]) -> Result<String> {
+    hash_algorithm(algorithm, bytes)
+}
+
 pub fn file_hash(algorithm: &str, path: &str) -> Result<String> {
     let mut bytes: Vec<u8> = Vec::new();
     let mut file = BufReader::new(File::open(path)?);
@@ -1,8 +1,16 @@
-use crate::{error::Result, jobs};
+use crate::{
+    byond::{deserialize_bool, deserialize_optional_bool},
+    error::{Error, Result},
+    jobs,
+};
+use base64::Engine;
 use once_cell::sync::Lazy;
+use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap};
-use std::io::Write;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime};
 
 // ----------------------------------------------------------------------------
 // Interface
@@ -13,6 +21,152 @@ struct RequestOptions {
     output_filename: Option<String>,
     #[serde(default)]
     body_filename: Option<String>,
+    /// How the response body is returned. Ignored with `output_filename`.
+    #[serde(default)]
+    body_encoding: BodyEncoding,
+    /// Bytes after which the response body is abandoned and the request fails.
+    #[serde(default)]
+    max_body_size: Option<u64>,
+    /// Resends the request when it fails in a way that may be temporary.
+    #[serde(default)]
+    retry: Option<RetryPolicy>,
+    /// Sends a multipart/form-data body made of these fields instead of `body`.
+    #[serde(default)]
+    multipart: Option<Vec<MultipartField>>,
+    /// Name of a profile made with `http_client_create` to send the request with.
+    #[serde(default)]
+    client: Option<String>,
+    #[serde(flatten)]
+    client_options: ClientOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    max_attempts: u32,
+    /// Seconds to wait before the first retry.
+    backoff: f64,
+    /// What the wait is multiplied by after each retry.
+    backoff_multiplier: f64,
+    /// Longest wait in seconds, Retry-After included.
+    max_backoff: f64,
+    /// Response statuses worth retrying.
+    on_status: Vec<u16>,
+    /// Whether to retry when connecting fails or times out.
+    #[serde(deserialize_with = "deserialize_bool")]
+    on_connection_error: bool,
+    /// Whether to wait as long as a Retry-After header asks, rather than the backoff.
+    #[serde(deserialize_with = "deserialize_bool")]
+    respect_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: 1.0,
+            backoff_multiplier: 2.0,
+            max_backoff: 30.0,
+            on_status: vec![429, 500, 502, 503, 504],
+            on_connection_error: true,
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before another go after `attempts` attempts ended in `response`,
+    /// or None if the response should be returned as it is.
+    fn delay_for_response(
+        &self,
+        response: &reqwest::blocking::Response,
+        attempts: u32,
+    ) -> Option<Duration> {
+        if !self.on_status.contains(&response.status().as_u16()) {
+            return None;
+        }
+        match retry_after(response) {
+            Some(seconds) if self.respect_retry_after => Some(self.clamp(seconds)),
+            _ => Some(self.backoff(attempts)),
+        }
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        self.clamp(self.backoff * self.backoff_multiplier.powi(exponent))
+    }
+
+    fn clamp(&self, seconds: f64) -> Duration {
+        Duration::try_from_secs_f64(seconds.min(self.max_backoff)).unwrap_or_default()
+    }
+}
+
+/// Reads a Retry-After header, in either its seconds or HTTP date form, as seconds from now.
+fn retry_after(response: &reqwest::blocking::Response) -> Option<f64> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds as f64);
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .as_secs_f64(),
+    )
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum BodyEncoding {
+    /// Decoded according to the response's charset.
+    #[default]
+    Text,
+    /// The raw bytes in base64, for binary responses.
+    Base64,
+}
+
+/// One field of a multipart body: either a text `value`, or the contents of the file at `path`.
+#[derive(Deserialize)]
+struct MultipartField {
+    name: String,
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    path: Option<String>,
+    /// Filename sent for the field. Defaults to that of `path`.
+    #[serde(default)]
+    filename: Option<String>,
+    /// Content type of the field. Defaults to one guessed from `path`, or text/plain for values.
+    #[serde(default)]
+    mime: Option<String>,
+}
+
+/// Settings for a client profile. Any of them given in a request's own options apply to just that request.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+struct ClientOptions {
+    /// Seconds the whole request, body included, may take.
+    timeout: Option<f64>,
+    /// Seconds connecting may take.
+    connect_timeout: Option<f64>,
+    #[serde(deserialize_with = "deserialize_optional_bool")]
+    follow_redirects: Option<bool>,
+    max_redirects: Option<usize>,
+    /// Proxy URL for every scheme, e.g. `http://127.0.0.1:3128` or `socks5://...`.
+    proxy: Option<String>,
+    /// Skips TLS certificate checks. For local development only.
+    #[serde(deserialize_with = "deserialize_optional_bool")]
+    accept_invalid_certs: Option<bool>,
+    /// Whether to ask for and transparently decode gzip, brotli and deflate compressed responses. Off by default,
+    /// so bodies arrive exactly as the server sent them.
+    #[serde(deserialize_with = "deserialize_optional_bool")]
+    decompress: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -20,6 +174,8 @@ struct Response<'a> {
     status_code: u16,
     headers: HashMap<&'a str, &'a str>,
     body: Option<&'a str>,
+    /// How many times the request was sent, retries included.
+    attempts: u32,
 }
 
 // If the response can be deserialized -> success.
@@ -30,6 +186,11 @@ byond_fn!(fn http_request_blocking(method, url, body, headers, options) {
         Err(e) => return Some(e.to_string())
     };
 
+    // Waiting between attempts here would hang the whole server.
+    if req.retry.is_some() {
+        return Some(Error::InvalidHttpOption("retry").to_string());
+    }
+
     match submit_request(req) {
         Ok(r) => Some(r),
         Err(e) => Some(e.to_string())
@@ -57,6 +218,19 @@ byond_fn!(fn http_check_request(id) {
     Some(jobs::check(id))
 });
 
+// Creates or replaces a named client profile, which requests select with the `client` option.
+byond_fn!(fn http_client_create(name, options) {
+    match create_client(name, options) {
+        Ok(()) => Some("true".to_owned()),
+        Err(e) => Some(e.to_string())
+    }
+});
+
+byond_fn!(fn http_client_remove(name) {
+    CLIENTS.with(|clients| clients.borrow_mut().remove(name));
+    Some("")
+});
+
 // ----------------------------------------------------------------------------
 // Shared HTTP client state
 
@@ -64,18 +238,92 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
 fn setup_http_client() -> reqwest::blocking::Client {
-    use reqwest::{
-        blocking::Client,
-        header::{HeaderMap, USER_AGENT},
-    };
+    ClientOptions::default().build().unwrap()
+}
+
+pub static HTTP_CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(setup_http_client);
+
+thread_local!( static CLIENTS: RefCell<HashMap<String, (ClientOptions, Client)>> = RefCell::new(HashMap::new()) );
+
+impl ClientOptions {
+    /// Whether any option needs a client of its own, rather than being settable per request.
+    fn needs_client(&self) -> bool {
+        self.connect_timeout.is_some()
+            || self.follow_redirects.is_some()
+            || self.max_redirects.is_some()
+            || self.proxy.is_some()
+            || self.accept_invalid_certs.is_some()
+            || self.decompress.is_some()
+    }
+
+    /// Takes each option from `overrides` where it is set, and from `self` otherwise.
+    fn overlay(&self, overrides: &ClientOptions) -> ClientOptions {
+        ClientOptions {
+            timeout: overrides.timeout.or(self.timeout),
+            connect_timeout: overrides.connect_timeout.or(self.connect_timeout),
+            follow_redirects: overrides.follow_redirects.or(self.follow_redirects),
+            max_redirects: overrides.max_redirects.or(self.max_redirects),
+            proxy: overrides.proxy.clone().or_else(|| self.proxy.clone()),
+            accept_invalid_certs: overrides.accept_invalid_certs.or(self.accept_invalid_certs),
+            decompress: overrides.decompress.or(self.decompress),
+        }
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(USER_AGENT, format!("{PKG_NAME}/{VERSION}").parse().unwrap());
+    fn build(&self) -> Result<Client> {
+        use reqwest::{
+            header::{HeaderMap, USER_AGENT},
+            redirect::Policy,
+            Proxy,
+        };
 
-    Client::builder().default_headers(headers).build().unwrap()
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, format!("{PKG_NAME}/{VERSION}").parse().unwrap());
+
+        let mut builder = Client::builder().default_headers(headers);
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(seconds(timeout, "timeout")?);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(seconds(timeout, "connect_timeout")?);
+        }
+        match (self.follow_redirects, self.max_redirects) {
+            (Some(false), _) => builder = builder.redirect(Policy::none()),
+            (_, Some(max)) => builder = builder.redirect(Policy::limited(max)),
+            _ => {}
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        if let Some(accept) = self.accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(accept);
+        }
+        if self.decompress != Some(true) {
+            builder = builder.no_gzip().no_brotli().no_deflate();
+        }
+
+        Ok(builder.build()?)
+    }
 }
 
-pub static HTTP_CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(setup_http_client);
+fn seconds(value: f64, option: &'static str) -> Result<Duration> {
+    Duration::try_from_secs_f64(value).map_err(|_| Error::InvalidHttpOption(option))
+}
+
+fn create_client(name: &str, options: &str) -> Result<()> {
+    // DM's json_encode gives "null" for no options and "[]" for an empty list.
+    let options: ClientOptions = match options {
+        "" | "null" | "[]" => ClientOptions::default(),
+        options => serde_json::from_str(options)?,
+    };
+    let client = options.build()?;
+    CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .insert(name.to_owned(), (options, client))
+    });
+    Ok(())
+}
 
 // ----------------------------------------------------------------------------
 // Request construction and execution
@@ -83,6 +331,11 @@ pub static HTTP_CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(setup_http_c
 struct RequestPrep {
     req: reqwest::blocking::RequestBuilder,
     output_filename: Option<String>,
+    body_encoding: BodyEncoding,
+    max_body_size: Option<u64>,
+    retry: Option<RetryPolicy>,
+    /// Kept apart from `req`, since a form can't be copied and has to be built again for each attempt.
+    multipart: Option<Vec<MultipartField>>,
 }
 
 fn construct_request(
@@ -92,13 +345,24 @@ fn construct_request(
     headers: &str,
     options: &str,
 ) -> Result<RequestPrep> {
+    // DM's json_encode gives "null" for no options and "[]" for an empty list.
+    let options: Option<RequestOptions> = match options {
+        "" | "null" | "[]" => None,
+        options => Some(serde_json::from_str(options)?),
+    };
+
+    let client = match &options {
+        Some(options) => choose_client(options)?,
+        None => HTTP_CLIENT.clone(),
+    };
+
     let mut req = match method {
-        "post" => HTTP_CLIENT.post(url),
-        "put" => HTTP_CLIENT.put(url),
-        "patch" => HTTP_CLIENT.patch(url),
-        "delete" => HTTP_CLIENT.delete(url),
-        "head" => HTTP_CLIENT.head(url),
-        _ => HTTP_CLIENT.get(url),
+        "post" => client.post(url),
+        "put" => client.put(url),
+        "patch" => client.patch(url),
+        "delete" => client.delete(url),
+        "head" => client.head(url),
+        _ => client.get(url),
     };
 
     if !body.is_empty() {
@@ -113,28 +377,95 @@ fn construct_request(
     }
 
     let mut output_filename = None;
-    if !options.is_empty() {
-        let options: RequestOptions = serde_json::from_str(options)?;
+    let mut body_encoding = BodyEncoding::default();
+    let mut max_body_size = None;
+    let mut retry = None;
+    let mut multipart = None;
+    if let Some(options) = options {
         output_filename = options.output_filename;
+        body_encoding = options.body_encoding;
+        max_body_size = options.max_body_size;
         if let Some(fname) = options.body_filename {
             req = req.body(std::fs::File::open(fname)?);
         }
+        if let Some(fields) = options.multipart {
+            // Catch a bad field or missing file now rather than from the job.
+            construct_form(&fields)?;
+            multipart = Some(fields);
+        }
+        if let Some(timeout) = options.client_options.timeout {
+            req = req.timeout(seconds(timeout, "timeout")?);
+        }
+        retry = options.retry;
+    }
+
+    // Retrying sends a copy of the request each time, which a body streamed from a file can't make.
+    if retry.as_ref().is_some_and(|retry| retry.max_attempts > 1) && req.try_clone().is_none() {
+        return Err(Error::InvalidHttpOption("retry"));
     }
 
     Ok(RequestPrep {
         req,
         output_filename,
+        body_encoding,
+        max_body_size,
+        retry,
+        multipart,
     })
 }
 
+/// Picks the profile named in the options, or the shared client, building a one-off client
+/// when the request's own options change anything that can only be set on a client.
+fn choose_client(options: &RequestOptions) -> Result<Client> {
+    let overrides = &options.client_options;
+    match &options.client {
+        Some(name) => CLIENTS.with(|clients| {
+            let clients = clients.borrow();
+            let (profile, client) = clients
+                .get(name)
+                .ok_or_else(|| Error::UnknownHttpClient(name.clone()))?;
+            match overrides.needs_client() {
+                true => profile.overlay(overrides).build(),
+                false => Ok(client.clone()),
+            }
+        }),
+        None if overrides.needs_client() => overrides.build(),
+        None => Ok(HTTP_CLIENT.clone()),
+    }
+}
+
+fn construct_form(fields: &[MultipartField]) -> Result<reqwest::blocking::multipart::Form> {
+    use reqwest::blocking::multipart::{Form, Part};
+
+    let mut form = Form::new();
+    for field in fields {
+        let mut part = match (&field.value, &field.path) {
+            (Some(value), None) => Part::text(value.clone()),
+            (None, Some(path)) => Part::file(path)?,
+            _ => return Err(Error::InvalidHttpOption("multipart")),
+        };
+        if let Some(filename) = &field.filename {
+            part = part.file_name(filename.clone());
+        }
+        if let Some(mime) = &field.mime {
+            part = part.mime_str(mime)?;
+        }
+        form = form.part(field.name.clone(), part);
+    }
+
+    Ok(form)
+}
+
 fn submit_request(prep: RequestPrep) -> Result<String> {
-    let mut response = prep.req.send()?;
+    let (mut response, attempts) =
+        send_with_retries(prep.req, prep.multipart.as_deref(), prep.retry.as_ref())?;
 
     let body;
     let mut resp = Response {
         status_code: response.status().as_u16(),
         headers: HashMap::new(),
         body: None,
+        attempts,
     };
 
     let headers = response.headers().clone();
@@ -144,14 +475,110 @@ fn submit_request(prep: RequestPrep) -> Result<String> {
         }
     }
 
+    if let (Some(max), Some(length)) = (prep.max_body_size, response.content_length()) {
+        if length > max {
+            return Err(Error::HttpBodyTooLarge(max));
+        }
+    }
+
     if let Some(output_filename) = prep.output_filename {
-        let mut writer = std::io::BufWriter::new(std::fs::File::create(output_filename)?);
-        std::io::copy(&mut response, &mut writer)?;
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&output_filename)?);
+        let copied = copy_limited(&mut response, &mut writer, prep.max_body_size);
         writer.flush()?;
+        if copied.is_err() {
+            drop(writer);
+            let _ = std::fs::remove_file(&output_filename);
+        }
+        copied?;
     } else {
-        body = response.text()?;
+        let mut bytes = Vec::new();
+        copy_limited(&mut response, &mut bytes, prep.max_body_size)?;
+        body = match prep.body_encoding {
+            BodyEncoding::Text => decode_text(&headers, &bytes),
+            BodyEncoding::Base64 => base64::prelude::BASE64_STANDARD.encode(bytes),
+        };
         resp.body = Some(&body);
     }
 
     Ok(serde_json::to_string(&resp)?)
 }
+
+/// Decodes a body by the charset in its Content-Type, falling back to UTF-8, as `Response::text` does.
+fn decode_text(headers: &reqwest::header::HeaderMap, bytes: &[u8]) -> String {
+    let charset = headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value.split(';').skip(1).find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("charset")
+                    .then(|| value.trim().trim_matches('"'))
+            })
+        });
+    let encoding = charset
+        .and_then(|charset| encoding_rs::Encoding::for_label(charset.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    encoding.decode(bytes).0.into_owned()
+}
+
+/// Sends the request, sending copies again as long as the retry policy allows.
+/// Each attempt gets a fresh multipart form, with any files read again.
+/// Returns the last response along with how many attempts it took.
+fn send_with_retries(
+    req: reqwest::blocking::RequestBuilder,
+    multipart: Option<&[MultipartField]>,
+    retry: Option<&RetryPolicy>,
+) -> Result<(reqwest::blocking::Response, u32)> {
+    let max_attempts = retry.map_or(1, |retry| retry.max_attempts.max(1));
+    let mut req = Some(req);
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        let last = attempts >= max_attempts;
+        let this = match last {
+            true => req.take(),
+            false => req.as_ref().and_then(|req| req.try_clone()),
+        }
+        .ok_or(Error::InvalidHttpOption("retry"))?;
+        let this = match multipart {
+            Some(fields) => this.multipart(construct_form(fields)?),
+            None => this,
+        };
+
+        let delay = match (this.send(), retry) {
+            (Ok(response), Some(retry)) if !last => {
+                match retry.delay_for_response(&response, attempts) {
+                    Some(delay) => delay,
+                    None => return Ok((response, attempts)),
+                }
+            }
+            (Ok(response), _) => return Ok((response, attempts)),
+            (Err(error), Some(retry))
+                if !last
+                    && retry.on_connection_error
+                    && (error.is_connect() || error.is_timeout()) =>
+            {
+                retry.backoff(attempts)
+            }
+            (Err(error), _) => return Err(error.into()),
+        };
+
+        std::thread::sleep(delay);
+    }
+}
+
+/// Copies the whole body, failing once it goes over `max` bytes. The Content-Length header is
+/// only a hint, since the body may be compressed or the server may not send one.
+fn copy_limited(reader: &mut impl Read, writer: &mut impl Write, max: Option<u64>) -> Result<u64> {
+    let Some(max) = max else {
+        return Ok(std::io::copy(reader, writer)?);
+    };
+
+    let copied = std::io::copy(&mut reader.take(max.saturating_add(1)), writer)?;
+    if copied > max {
+        return Err(Error::HttpBodyTooLarge(max));
+    }
+    Ok(copied)
+}
@@ -0,0 +1,386 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Request, Response, Server};
+
+const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+const DEFAULT_TIMEOUT: f32 = 30.0;
+const DEFAULT_MAX_PENDING: usize = 1000;
+
+/// How long the server thread waits for a new connection before checking on DM again.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+thread_local! {
+    static REQUEST_SENDER: RefCell<Option<flume::Sender<ServerRequest>>> = const { RefCell::new(None) };
+    static RESPONSE_RECEIVER: RefCell<Option<flume::Receiver<ServerEvent>>> = const { RefCell::new(None) };
+    static SERVER_THREAD: RefCell<Option<JoinHandle<()>>> = const { RefCell::new(None) };
+}
+
+/// Routes are kept outside the server so they can be registered before it starts and survive restarts.
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+#[derive(PartialEq)]
+struct Route {
+    /// Uppercase method, or `*` for any.
+    method: String,
+    /// Exact path, or a prefix when it ends in `*`.
+    path: String,
+}
+
+impl Route {
+    fn new(method: &str, path: &str) -> Self {
+        Self {
+            method: method.to_ascii_uppercase(),
+            path: path.to_owned(),
+        }
+    }
+
+    fn matches(&self, method: &str, path: &str) -> bool {
+        (self.method == "*" || self.method.eq_ignore_ascii_case(method))
+            && match self.path.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == self.path,
+            }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct ServerOptions {
+    /// Requests with a bigger body are answered with 413 without reaching DM.
+    max_body_size: u64,
+    /// Seconds DM has to respond before the server answers with 504 itself.
+    timeout: f32,
+    /// The most requests being read or waiting on DM at once. Any more are answered with 503.
+    max_pending: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+}
+
+enum ServerRequest {
+    Respond(u64, Response<std::io::Cursor<Vec<u8>>>),
+}
+
+enum ServerEvent {
+    Request(IncomingRequest),
+    Stopped(String),
+}
+
+#[derive(Serialize)]
+struct IncomingRequest {
+    /// A string, since DM would print large numbers in scientific notation when passing them back.
+    id: String,
+    method: String,
+    path: String,
+    query: String,
+    /// Lowercase names. Repeated headers are joined with commas.
+    headers: BTreeMap<String, String>,
+    body: String,
+    remote_addr: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum PollEntry {
+    Request(IncomingRequest),
+    Error { error: String },
+}
+
+struct Pending {
+    request: Request,
+    deadline: Instant,
+}
+
+/// A request back from its reader thread, with its body or None if that was too big.
+type ReadRequest = (Request, Option<Vec<u8>>);
+
+fn reply(request: Request, status: u16) {
+    let _ = request.respond(Response::empty(status));
+}
+
+/// Reads the body, or returns None if it is over `limit` bytes.
+fn read_body(request: &mut Request, limit: u64) -> Option<Vec<u8>> {
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > limit)
+    {
+        return None;
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut body)
+        .ok()?;
+    (body.len() as u64 <= limit).then_some(body)
+}
+
+fn describe(request: &Request, id: u64, body: Vec<u8>) -> IncomingRequest {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+
+    let mut headers: BTreeMap<String, String> = BTreeMap::new();
+    for header in request.headers() {
+        let name = header.field.as_str().as_str().to_ascii_lowercase();
+        let value = header.value.as_str();
+        headers
+            .entry(name)
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.to_owned());
+    }
+
+    IncomingRequest {
+        id: id.to_string(),
+        method: request.method().as_str().to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        remote_addr: request.remote_addr().map(|addr| addr.to_string()),
+    }
+}
+
+fn serve_inner(
+    server: &Server,
+    options: &ServerOptions,
+    timeout: Duration,
+    control: &flume::Receiver<ServerRequest>,
+    out: &flume::Sender<ServerEvent>,
+    pending: &mut HashMap<u64, Pending>,
+) -> std::io::Result<()> {
+    let mut next_id: u64 = 0;
+    let (read_sender, read_receiver) = flume::unbounded::<ReadRequest>();
+    let mut reading: usize = 0;
+
+    loop {
+        loop {
+            match control.try_recv() {
+                Ok(ServerRequest::Respond(id, response)) => {
+                    if let Some(waiting) = pending.remove(&id) {
+                        let _ = waiting.request.respond(response);
+                    }
+                }
+                Err(flume::TryRecvError::Empty) => break,
+                Err(flume::TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        let now = Instant::now();
+        let expired: Vec<u64> = pending
+            .iter()
+            .filter(|(_, waiting)| waiting.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some(waiting) = pending.remove(&id) {
+                reply(waiting.request, 504);
+            }
+        }
+
+        for (request, body) in read_receiver.try_iter() {
+            reading -= 1;
+            let Some(body) = body else {
+                reply(request, 413);
+                continue;
+            };
+
+            next_id += 1;
+            match out.try_send(ServerEvent::Request(describe(&request, next_id, body))) {
+                Ok(()) => {
+                    pending.insert(
+                        next_id,
+                        Pending {
+                            request,
+                            deadline: Instant::now() + timeout,
+                        },
+                    );
+                }
+                Err(flume::TrySendError::Full(_)) => reply(request, 503),
+                Err(flume::TrySendError::Disconnected(_)) => return Ok(()), // Nobody is polling any more
+            }
+        }
+
+        let Some(mut request) = server.recv_timeout(POLL_INTERVAL)? else {
+            continue;
+        };
+
+        let path = request.url().split('?').next().unwrap_or_default();
+        let routed = ROUTES
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|route| route.matches(request.method().as_str(), path));
+        if !routed {
+            reply(request, 404);
+            continue;
+        }
+        if pending.len() + reading >= options.max_pending {
+            reply(request, 503);
+            continue;
+        }
+
+        // Bodies are read on a thread of their own, so a slow upload can't hold up every other request.
+        reading += 1;
+        let read_sender = read_sender.clone();
+        let limit = options.max_body_size;
+        thread::spawn(move || {
+            let body = read_body(&mut request, limit);
+            if let Err(flume::SendError((request, _))) = read_sender.send((request, body)) {
+                reply(request, 503); // The server stopped meanwhile
+            }
+        });
+    }
+}
+
+fn serve(
+    server: Server,
+    options: ServerOptions,
+    timeout: Duration,
+    control: flume::Receiver<ServerRequest>,
+    out: flume::Sender<ServerEvent>,
+) {
+    let mut pending = HashMap::new();
+    if let Err(e) = serve_inner(&server, &options, timeout, &control, &out, &mut pending) {
+        let _ = out.send(ServerEvent::Stopped(e.to_string()));
+    }
+    // Don't leave clients hanging on requests DM will never see answered.
+    for (_, waiting) in pending.drain() {
+        reply(waiting.request, 503);
+    }
+}
+
+fn start(address: &str, options: &str) -> Result<(), String> {
+    // DM's json_encode gives "null" for no options and "[]" for an empty list.
+    let options: ServerOptions = match options {
+        "" | "null" | "[]" => ServerOptions::default(),
+        options => serde_json::from_str(options).map_err(|e| e.to_string())?,
+    };
+    // Checked here, since the server thread couldn't report it.
+    let timeout = Duration::try_from_secs_f32(options.timeout.max(0.0))
+        .map_err(|_| format!("Invalid timeout: {}", options.timeout))?;
+    // A bare port listens on every interface, like prom_init.
+    let address = match address.parse::<u16>() {
+        Ok(port) => format!("0.0.0.0:{port}"),
+        Err(_) => address.to_owned(),
+    };
+
+    stop();
+    let server = Server::http(address).map_err(|e| e.to_string())?;
+    let (c_sender, c_receiver) = flume::bounded(DEFAULT_MAX_PENDING);
+    let (o_sender, o_receiver) = flume::bounded(DEFAULT_MAX_PENDING);
+    REQUEST_SENDER.with(|cell| cell.replace(Some(c_sender)));
+    RESPONSE_RECEIVER.with(|cell| cell.replace(Some(o_receiver)));
+    let handle = thread::spawn(move || serve(server, options, timeout, c_receiver, o_sender));
+    SERVER_THREAD.with(|cell| cell.replace(Some(handle)));
+    Ok(())
+}
+
+fn stop() {
+    // Dropping the sender and receiver will cause the other thread to exit
+    REQUEST_SENDER.with(|cell| {
+        cell.replace(None);
+    });
+    RESPONSE_RECEIVER.with(|cell| {
+        cell.replace(None);
+    });
+    // Wait for it, so the address is free again once this returns.
+    if let Some(handle) = SERVER_THREAD.with(|cell| cell.replace(None)) {
+        let _ = handle.join();
+    }
+}
+
+fn respond(id: &str, status: &str, headers: &str, body: &str) -> Result<(), String> {
+    let id: u64 = id
+        .parse()
+        .map_err(|_| format!("Invalid request id: {id}"))?;
+    let status: u16 = status
+        .parse()
+        .map_err(|_| format!("Invalid status: {status}"))?;
+    let headers: BTreeMap<String, String> = match headers {
+        "" | "null" | "[]" => BTreeMap::new(),
+        headers => serde_json::from_str(headers).map_err(|e| e.to_string())?,
+    };
+
+    let mut response = Response::from_data(body.as_bytes().to_vec()).with_status_code(status);
+    for (name, value) in &headers {
+        let header = Header::from_bytes(name.as_bytes(), value.as_bytes())
+            .map_err(|_| format!("Invalid header: {name}"))?;
+        response.add_header(header);
+    }
+
+    REQUEST_SENDER.with(|cell| {
+        if let Some(chan) = cell.borrow().as_ref() {
+            chan.try_send(ServerRequest::Respond(id, response))
+                .map_err(|e| e.to_string())
+        } else {
+            Err("Not running".to_owned())
+        }
+    })
+}
+
+fn poll() -> String {
+    let mut result = Vec::new();
+
+    RESPONSE_RECEIVER.with(|cell| {
+        if let Some(recv) = cell.borrow().as_ref() {
+            result.extend(recv.try_iter().map(|event| match event {
+                ServerEvent::Request(request) => PollEntry::Request(request),
+                ServerEvent::Stopped(error) => PollEntry::Error { error },
+            }));
+        }
+    });
+
+    serde_json::to_string(&result).unwrap_or_else(|_| "[]".to_owned())
+}
+
+byond_fn!(fn http_server_start(address, options) {
+    start(address, options).err()
+});
+
+byond_fn!(
+    fn http_server_stop() {
+        stop();
+        Some("")
+    }
+);
+
+byond_fn!(fn http_server_route(method, path) {
+    let route = Route::new(method, path);
+    let mut routes = ROUTES.lock().unwrap();
+    if !routes.contains(&route) {
+        routes.push(route);
+    }
+    Some("")
+});
+
+byond_fn!(fn http_server_unroute(method, path) {
+    let route = Route::new(method, path);
+    ROUTES.lock().unwrap().retain(|existing| *existing != route);
+    Some("")
+});
+
+byond_fn!(
+    fn http_server_poll() {
+        Some(poll())
+    }
+);
+
+byond_fn!(fn http_server_respond(id, status, headers, body) {
+    respond(id, status, headers, body).err()
+});
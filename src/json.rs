@@ -1,5 +1,10 @@
+use crate::error::{respond, Error, Result};
+use json_patch::Patch;
+use jsonschema::Validator;
+use serde::Serialize;
 use serde_json::Value;
-use std::cmp;
+use serde_json_path::JsonPath;
+use std::{cell::RefCell, cmp, collections::HashMap};
 
 const VALID_JSON_MAX_RECURSION_DEPTH: usize = 8;
 
@@ -12,9 +17,219 @@ byond_fn!(fn json_is_valid(text) {
     Some(get_recursion_level(&value).is_ok().to_string())
 });
 
+// Like `json_is_valid`, but with a configurable max depth and size (either may be empty for no limit),
+// and says where and why the text was rejected.
+byond_fn!(fn json_check(text, max_depth, max_size) {
+    let report = match check_json(text, max_depth, max_size) {
+        Ok(report) => report,
+        Err(error) => JsonReport::invalid(None, error.to_string()),
+    };
+    serde_json::to_string(&report).ok()
+});
+
+#[derive(Serialize)]
+struct JsonReport {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl JsonReport {
+    fn invalid(position: Option<(usize, usize)>, reason: String) -> Self {
+        Self {
+            valid: false,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            reason: Some(reason),
+        }
+    }
+}
+
+fn check_json(text: &str, max_depth: &str, max_size: &str) -> Result<JsonReport> {
+    if !max_size.is_empty() {
+        let max_size: usize = max_size.parse()?;
+        if text.len() > max_size {
+            return Ok(JsonReport::invalid(
+                None,
+                format!(
+                    "Document is {} bytes, over the limit of {max_size}.",
+                    text.len()
+                ),
+            ));
+        }
+    }
+
+    if let Err(error) = serde_json::from_str::<serde::de::IgnoredAny>(text) {
+        // serde_json's messages end with their own " at line X column Y".
+        let reason = error.to_string();
+        let reason = match reason.rfind(" at line ") {
+            Some(index) => reason[..index].to_owned(),
+            None => reason,
+        };
+        return Ok(JsonReport::invalid(
+            Some((error.line(), error.column())),
+            reason,
+        ));
+    }
+
+    if !max_depth.is_empty() {
+        let max_depth: usize = max_depth.parse()?;
+        if let Some(position) = find_too_deep(text, max_depth) {
+            return Ok(JsonReport::invalid(
+                Some(position),
+                format!("Nested deeper than the limit of {max_depth}."),
+            ));
+        }
+    }
+
+    Ok(JsonReport {
+        valid: true,
+        line: None,
+        column: None,
+        reason: None,
+    })
+}
+
+/// Finds the line and column of the first array or object opened deeper than `max_depth`
+/// in syntactically valid JSON text. Columns count bytes, as serde_json's do.
+fn find_too_deep(text: &str, max_depth: usize) -> Option<(usize, usize)> {
+    let (mut depth, mut line, mut line_start) = (0, 1, 0);
+    let (mut in_string, mut escaped) = (false, false);
+
+    for (index, byte) in text.bytes().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'\n' => {
+                line += 1;
+                line_start = index + 1;
+            }
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Some((line, index - line_start + 1));
+                }
+            }
+            b']' | b'}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+thread_local!( static SCHEMAS: RefCell<HashMap<String, Validator>> = RefCell::new(HashMap::new()) );
+
+// Compiles `schema` and stores it under `key`, replacing whatever was registered there before.
+byond_fn!(fn json_schema_register(key, schema) {
+    respond(json_schema_register_impl(key, schema))
+});
+
+byond_fn!(fn json_schema_unregister(key) {
+    SCHEMAS.with(|schemas| schemas.borrow_mut().remove(key));
+    Some("")
+});
+
+// Returns the list of violations of the schema registered under `key`, empty if `text` is valid.
+byond_fn!(fn json_schema_validate(key, text) {
+    respond(json_schema_validate_impl(key, text))
+});
+
+#[derive(Serialize)]
+struct Violation {
+    /// JSON pointer to the offending value in the document.
+    pointer: String,
+    /// JSON pointer to the schema keyword that rejected it.
+    schema_pointer: String,
+    message: String,
+}
+
+fn json_schema_register_impl(key: &str, schema: &str) -> Result<()> {
+    let validator = jsonschema::validator_for(&serde_json::from_str(schema)?)
+        .map_err(|error| Error::InvalidJsonSchema(error.to_string()))?;
+    SCHEMAS.with(|schemas| schemas.borrow_mut().insert(key.to_owned(), validator));
+    Ok(())
+}
+
+fn json_schema_validate_impl(key: &str, text: &str) -> Result<Vec<Violation>> {
+    let instance: Value = serde_json::from_str(text)?;
+    SCHEMAS.with(|schemas| {
+        let schemas = schemas.borrow();
+        let validator = schemas
+            .get(key)
+            .ok_or_else(|| Error::UnknownJsonSchema(key.to_owned()))?;
+
+        Ok(validator
+            .iter_errors(&instance)
+            .map(|error| Violation {
+                pointer: error.instance_path.to_string(),
+                schema_pointer: error.schema_path.to_string(),
+                message: error.to_string(),
+            })
+            .collect())
+    })
+}
+
+// Returns the value at the RFC 6901 JSON pointer `pointer`, or null if there is none.
+byond_fn!(fn json_pointer_get(text, pointer) {
+    respond(json_pointer_get_impl(text, pointer))
+});
+
+// Returns the list of values matched by the RFC 9535 JSONPath expression `path`.
+byond_fn!(fn json_path_query(text, path) {
+    respond(json_path_query_impl(text, path))
+});
+
+// Applies an RFC 6902 JSON Patch. Either every operation applies or the document is left as is.
+byond_fn!(fn json_patch(text, patch) {
+    respond(json_patch_impl(text, patch))
+});
+
+// Applies an RFC 7386 JSON merge patch.
+byond_fn!(fn json_merge_patch(text, patch) {
+    respond(json_merge_patch_impl(text, patch))
+});
+
+fn json_pointer_get_impl(text: &str, pointer: &str) -> Result<Value> {
+    let document: Value = serde_json::from_str(text)?;
+    Ok(document.pointer(pointer).cloned().unwrap_or(Value::Null))
+}
+
+fn json_path_query_impl(text: &str, path: &str) -> Result<Vec<Value>> {
+    let path = JsonPath::parse(path)?;
+    let document: Value = serde_json::from_str(text)?;
+    Ok(path.query(&document).all().into_iter().cloned().collect())
+}
+
+fn json_patch_impl(text: &str, patch: &str) -> Result<String> {
+    let mut document: Value = serde_json::from_str(text)?;
+    let patch: Patch = serde_json::from_str(patch)?;
+    json_patch::patch(&mut document, &patch)?;
+    Ok(serde_json::to_string(&document)?)
+}
+
+fn json_merge_patch_impl(text: &str, patch: &str) -> Result<String> {
+    let mut document: Value = serde_json::from_str(text)?;
+    json_patch::merge(&mut document, &serde_json::from_str(patch)?);
+    Ok(serde_json::to_string(&document)?)
+}
+
 /// Gets the recursion level of the given value
 /// If it is above `VALID_JSON_MAX_RECURSION_DEPTH`, returns Err(())
-fn get_recursion_level(value: &Value) -> Result<usize, ()> {
+fn get_recursion_level(value: &Value) -> std::result::Result<usize, ()> {
     let values: Vec<&Value> = match value {
         Value::Array(array) => array.iter().collect(),
 
@@ -58,6 +273,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_find_too_deep() {
+        assert_eq!(find_too_deep("[[]]", 2), None);
+        assert_eq!(find_too_deep("[[[]]]", 2), Some((1, 3)));
+        assert_eq!(
+            find_too_deep("{\n  \"a\": \"[[\\\"[\",\n  \"b\": [[1]]\n}", 2),
+            Some((3, 9))
+        );
+    }
+
     #[test]
     fn test_get_recursion_level_max_depth() {
         assert_eq!(
@@ -7,6 +7,8 @@ mod error;
 
 #[cfg(feature = "jobs")]
 mod jobs;
+#[cfg(any(feature = "log", feature = "time"))]
+mod zone;
 
 #[cfg(feature = "acreplace")]
 pub mod acreplace;
@@ -14,6 +16,8 @@ pub mod acreplace;
 pub mod cellularnoise;
 #[cfg(feature = "cfg")]
 pub mod cfg;
+#[cfg(feature = "data")]
+pub mod data;
 #[cfg(feature = "dbpnoise")]
 pub mod dbpnoise;
 #[cfg(feature = "dmi")]
@@ -26,6 +30,8 @@ pub mod git;
 pub mod hash;
 #[cfg(feature = "http")]
 pub mod http;
+#[cfg(feature = "http_server")]
+pub mod http_server;
 #[cfg(feature = "iconforge")]
 pub mod iconforge;
 #[cfg(feature = "json")]
@@ -56,6 +62,8 @@ pub mod toml;
 pub mod unzip;
 #[cfg(feature = "url")]
 pub mod url;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 #[cfg(feature = "worleynoise")]
 pub mod worleynoise;
 
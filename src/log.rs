@@ -1,59 +1,509 @@
-use crate::error::Result;
-use chrono::Utc;
+use crate::{
+    byond::deserialize_bool,
+    error::{respond, Result},
+    jobs,
+    zone::Zone,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::{write::GzEncoder, Compression};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
-    collections::hash_map::{Entry, HashMap},
-    ffi::OsString,
+    collections::HashMap,
+    fmt::Write as _,
     fs,
     fs::{File, OpenOptions},
-    io::Write,
-    path::Path,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread,
 };
 
+/// How many writes may be queued before `log_write` blocks on the writer thread.
+const QUEUE_SIZE: usize = 4096;
+
+/// How many write errors are kept for the next flush. Any more are only counted, so a file
+/// that can't be written doesn't grow the list for the rest of the round.
+const MAX_ERRORS: usize = 100;
+
 thread_local! {
-    static FILE_MAP: RefCell<HashMap<OsString, File>> = RefCell::new(HashMap::new());
+    static WRITER: RefCell<Option<flume::Sender<LogRequest>>> = const { RefCell::new(None) };
+    static FORMAT_MAP: RefCell<HashMap<PathBuf, LineFormat>> = RefCell::new(HashMap::new());
 }
 
-byond_fn!(fn log_write(path, data, ...rest) {
-    FILE_MAP.with(|cell| -> Result<()> {
-        // open file
-        let mut map = cell.borrow_mut();
-        let path = Path::new(path as &str);
-        let file = match map.entry(path.into()) {
-            Entry::Occupied(elem) => elem.into_mut(),
-            Entry::Vacant(elem) => elem.insert(open(path)?),
-        };
+/// Everything goes through one queue, so writes to a file land in the order they were made.
+enum LogRequest {
+    Write(PathBuf, String),
+    SetRotation(PathBuf, Option<Rotation>),
+    /// Flushes every open file and replies with the errors collected since the last flush.
+    Flush(flume::Sender<Vec<String>>),
+    /// Like `Flush`, but also closes every file.
+    CloseAll(flume::Sender<Vec<String>>),
+    /// Flushes every open file, leaving any errors for the next `Flush`.
+    Sync(flume::Sender<()>),
+}
 
-        if rest.first().map(|x| &**x) == Some("false") {
-            // Write the data to the file with no accoutrements.
-            write!(file, "{data}")?;
-        } else {
-            // write first line, timestamped
-            let mut iter = data.split('\n');
-            if let Some(line) = iter.next() {
-                writeln!(file, "[{}] {}", Utc::now().format("%F %T%.3f"), line)?;
-            }
+/// State owned by the writer thread.
+#[derive(Default)]
+struct Writer {
+    files: HashMap<PathBuf, LogFile>,
+    rotations: HashMap<PathBuf, Rotation>,
+    /// Errors from writes, which DM only hears about on the next flush.
+    errors: Vec<String>,
+    /// Errors that didn't fit in `errors`.
+    dropped_errors: usize,
+}
+
+/// An open log file, along with what rotation needs to know about it.
+struct LogFile {
+    file: BufWriter<File>,
+    size: u64,
+    /// When the file was first written to, as far as we can tell.
+    started: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Clone)]
+struct Rotation {
+    /// Rotate before a write would take the file past this many bytes.
+    #[serde(default)]
+    max_size: Option<u64>,
+    /// Rotate when the file was started in an earlier period than now.
+    #[serde(default)]
+    interval: Option<Interval>,
+    /// How many rotated files to retain, as `name.1` (newest) through `name.<keep>`.
+    #[serde(default = "default_keep")]
+    keep: usize,
+    /// Gzip rotated files, naming them `name.1.gz` and so on.
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    compress: bool,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Interval {
+    Hourly,
+    Daily,
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// How `log_write` stamps the lines of a file. Lives on the calling thread, since lines are
+/// timestamped when they are written rather than when they reach the disk.
+struct LineFormat {
+    timestamp_format: String,
+    zone: Zone,
+    /// For round-relative timestamps, the moment they count from.
+    relative_to: Option<DateTime<Utc>>,
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+struct LineFormatOptions {
+    /// A strftime pattern. An empty string gives RFC 3339.
+    #[serde(default = "default_timestamp_format")]
+    timestamp_format: String,
+    #[serde(default)]
+    timezone: String,
+    /// Stamp lines with the time elapsed since the format was set instead.
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    relative: bool,
+    /// Written at the start of every timestamped line, e.g. a round id.
+    #[serde(default)]
+    prefix: String,
+}
 
-            // write remaining lines
-            for line in iter {
-                writeln!(file, " - {line}")?;
+fn default_timestamp_format() -> String {
+    String::from("%F %T%.3f")
+}
+
+// The line is formatted (and timestamped) here, then handed to the writer thread.
+// Only returns an error if the writer thread is gone; write errors are reported by `log_flush`.
+byond_fn!(fn log_write(path, data, ...rest) {
+    let mut buffer = String::new();
+    if rest.first().map(|x| &**x) == Some("false") {
+        // Write the data to the file with no accoutrements.
+        buffer.push_str(data);
+    } else {
+        // write first line, timestamped
+        let mut iter = data.split('\n');
+        if let Some(line) = iter.next() {
+            let now = Utc::now();
+            let stamped = FORMAT_MAP.with(|cell| {
+                cell.borrow()
+                    .get(Path::new(path as &str))
+                    .map(|format| format.stamp(now, line))
+            });
+            match stamped {
+                Some(Ok(stamped)) => buffer.push_str(&stamped),
+                Some(Err(error)) => return Some(error.to_string()),
+                None => {
+                    let _ = write!(buffer, "[{}] {}", now.format("%F %T%.3f"), line);
+                }
             }
+            buffer.push('\n');
         }
 
-        Ok(())
-    }).err()
+        // write remaining lines
+        for line in iter {
+            let _ = writeln!(buffer, " - {line}");
+        }
+    }
+
+    send(LogRequest::Write(path.into(), buffer)).err()
+});
+
+// Writes one JSON object per line, for log shippers. `fields` must be a JSON object.
+byond_fn!(fn log_write_json(path, category, fields) {
+    // DM's json_encode gives "null" for no fields and "[]" for an empty list.
+    let fields = match fields {
+        "" | "null" | "[]" => serde_json::Value::Object(Default::default()),
+        fields => match serde_json::from_str::<serde_json::Value>(fields) {
+            Ok(fields @ serde_json::Value::Object(_)) => fields,
+            Ok(_) => return Some(String::from("fields must be a JSON object")),
+            Err(error) => return Some(error.to_string()),
+        },
+    };
+
+    let mut line = serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "category": category,
+        "fields": fields,
+    })
+    .to_string();
+    line.push('\n');
+
+    send(LogRequest::Write(path.into(), line)).err()
 });
 
+// Like rotation, line formats survive `log_close_all`. Pass "null" to go back to the default.
+byond_fn!(fn log_set_format(path, options) {
+    let format = match options {
+        "" | "null" => None,
+        options => match LineFormat::parse(options) {
+            Ok(format) => Some(format),
+            Err(error) => return Some(error.to_string()),
+        },
+    };
+
+    FORMAT_MAP.with(|cell| {
+        let mut map = cell.borrow_mut();
+        match format {
+            Some(format) => map.insert(path.into(), format),
+            None => map.remove(Path::new(path as &str)),
+        }
+    });
+    Some(String::new())
+});
+
+// Blocks until everything queued so far is on disk.
+byond_fn!(
+    fn log_flush() {
+        Some(request_with_reply(LogRequest::Flush))
+    }
+);
+
+// Flushes and closes every file. Nothing queued before this call is lost.
 byond_fn!(
     fn log_close_all() {
-        FILE_MAP.with(|cell| {
-            let mut map = cell.borrow_mut();
-            map.clear();
-        });
-        Some("")
+        Some(request_with_reply(LogRequest::CloseAll))
     }
 );
 
+// Rotation settings survive `log_close_all`. Pass "null" to stop rotating a file.
+byond_fn!(fn log_set_rotation(path, options) {
+    let rotation = match options {
+        "" | "null" => None,
+        options => match serde_json::from_str::<Rotation>(options) {
+            Ok(rotation) => Some(rotation),
+            Err(error) => return Some(error.to_string()),
+        },
+    };
+
+    Some(send(LogRequest::SetRotation(path.into(), rotation)).err().unwrap_or_default())
+});
+
+byond_fn!(fn log_tail(path, count) {
+    sync();
+    respond(count.parse().map_err(Into::into).and_then(|count| tail(path, count)))
+});
+
+byond_fn!(fn log_grep(path, pattern, options) {
+    sync();
+    respond(GrepOptions::parse(options).and_then(|options| grep(path, pattern, &options)))
+});
+
+// Returns new job-id.
+byond_fn!(fn log_grep_async(path, pattern, options) {
+    sync();
+    // Bad options are reported through the job too, so DM always gets a job id back.
+    let options = GrepOptions::parse(options);
+    let (path, pattern) = (path.to_owned(), pattern.to_owned());
+    Some(jobs::start(move || {
+        respond(options.and_then(|options| grep(&path, &pattern, &options))).unwrap_or_default()
+    }))
+});
+
+byond_fn!(fn log_check_job(id) {
+    Some(jobs::check(id))
+});
+
+/// Queues a request for the writer thread, starting it if needed.
+fn send(request: LogRequest) -> std::result::Result<(), String> {
+    WRITER.with(|cell| {
+        let mut writer = cell.borrow_mut();
+        let sender = writer.get_or_insert_with(|| {
+            let (sender, receiver) = flume::bounded(QUEUE_SIZE);
+            thread::spawn(move || Writer::default().run(receiver));
+            sender
+        });
+
+        sender.send(request).map_err(|_| {
+            // The thread died. Forget it, so the next call starts a fresh one.
+            *writer = None;
+            String::from("Log writer thread is not running")
+        })
+    })
+}
+
+/// Makes sure queued writes are on disk before a file is read back.
+fn sync() {
+    let (reply, response) = flume::bounded(1);
+    if send(LogRequest::Sync(reply)).is_ok() {
+        let _ = response.recv();
+    }
+}
+
+fn request_with_reply(request: fn(flume::Sender<Vec<String>>) -> LogRequest) -> String {
+    let (reply, response) = flume::bounded(1);
+    if let Err(error) = send(request(reply)) {
+        return error;
+    }
+
+    match response.recv() {
+        Ok(errors) => errors.join("\n"),
+        Err(_) => String::from("Log writer thread is not running"),
+    }
+}
+
+impl Writer {
+    fn run(mut self, receiver: flume::Receiver<LogRequest>) {
+        while let Ok(request) = receiver.recv() {
+            self.handle(request);
+
+            // Batch up whatever else is already waiting, then flush once the queue runs dry.
+            while let Ok(request) = receiver.try_recv() {
+                self.handle(request);
+            }
+            self.flush();
+        }
+    }
+
+    fn handle(&mut self, request: LogRequest) {
+        match request {
+            LogRequest::Write(path, data) => {
+                if let Err(error) = self.write(&path, &data) {
+                    self.error(&path, error);
+                }
+            }
+            LogRequest::SetRotation(path, Some(rotation)) => {
+                self.rotations.insert(path, rotation);
+            }
+            LogRequest::SetRotation(path, None) => {
+                self.rotations.remove(&path);
+            }
+            LogRequest::Flush(reply) => {
+                self.flush();
+                let _ = reply.send(self.take_errors());
+            }
+            LogRequest::CloseAll(reply) => {
+                self.flush();
+                self.files.clear();
+                let _ = reply.send(self.take_errors());
+            }
+            LogRequest::Sync(reply) => {
+                self.flush();
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    fn write(&mut self, path: &Path, data: &str) -> Result<()> {
+        if !self.files.contains_key(path) {
+            self.files.insert(path.to_owned(), LogFile::open(path)?);
+        }
+
+        if let Some(rotation) = self.rotations.get(path) {
+            if rotation.is_due(&self.files[path], data.len() as u64) {
+                // The handle has to be closed before the file can be renamed on Windows.
+                if let Some(mut file) = self.files.remove(path) {
+                    file.file.flush()?;
+                }
+                rotation.rotate(path)?;
+                self.files.insert(path.to_owned(), LogFile::open(path)?);
+            }
+        }
+
+        let file = self.files.get_mut(path).unwrap();
+        file.file.write_all(data.as_bytes())?;
+        file.size += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        let mut failed = Vec::new();
+        for (path, file) in &mut self.files {
+            if let Err(error) = file.file.flush() {
+                failed.push((path.clone(), error));
+            }
+        }
+        for (path, error) in failed {
+            self.error(&path, error);
+        }
+    }
+
+    fn error(&mut self, path: &Path, error: impl std::fmt::Display) {
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(format!("{}: {error}", path.display()));
+        } else {
+            self.dropped_errors += 1;
+        }
+    }
+
+    fn take_errors(&mut self) -> Vec<String> {
+        let mut errors = std::mem::take(&mut self.errors);
+        if self.dropped_errors > 0 {
+            errors.push(format!("...and {} more", self.dropped_errors));
+            self.dropped_errors = 0;
+        }
+        errors
+    }
+}
+
+impl LineFormat {
+    fn parse(options: &str) -> Result<Self> {
+        let options: LineFormatOptions = serde_json::from_str(options)?;
+        let now = Utc::now();
+        let format = Self {
+            timestamp_format: options.timestamp_format,
+            zone: Zone::parse(&options.timezone)?,
+            relative_to: options.relative.then_some(now),
+            prefix: options.prefix,
+        };
+
+        // Catch a bad pattern now, rather than on every write.
+        format.stamp(now, "")?;
+        Ok(format)
+    }
+
+    fn stamp(&self, now: DateTime<Utc>, line: &str) -> Result<String> {
+        let timestamp = match self.relative_to {
+            Some(start) => {
+                let elapsed = (now - start).num_milliseconds().max(0);
+                format!(
+                    "{:02}:{:02}:{:02}.{:03}",
+                    elapsed / 3_600_000,
+                    elapsed / 60_000 % 60,
+                    elapsed / 1000 % 60,
+                    elapsed % 1000
+                )
+            }
+            None => self.zone.format(&now, &self.timestamp_format)?,
+        };
+
+        Ok(format!("{}[{timestamp}] {line}", self.prefix))
+    }
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = open(path)?;
+        let metadata = file.metadata()?;
+        let started = match metadata.modified() {
+            Ok(modified) if metadata.len() > 0 => modified.into(),
+            _ => Utc::now(),
+        };
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            size: metadata.len(),
+            started,
+        })
+    }
+}
+
+impl Interval {
+    fn period(self, time: DateTime<Utc>) -> String {
+        match self {
+            Self::Hourly => time.format("%F %H").to_string(),
+            Self::Daily => time.format("%F").to_string(),
+        }
+    }
+}
+
+impl Rotation {
+    fn is_due(&self, file: &LogFile, incoming: u64) -> bool {
+        // Never rotate away an empty file.
+        if file.size == 0 {
+            return false;
+        }
+
+        let too_big = self
+            .max_size
+            .is_some_and(|max_size| file.size + incoming > max_size);
+        let too_old = self
+            .interval
+            .is_some_and(|interval| interval.period(file.started) != interval.period(Utc::now()));
+
+        too_big || too_old
+    }
+
+    fn rotate(&self, path: &Path) -> Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+
+        let suffix = if self.compress { ".gz" } else { "" };
+        let numbered = |n: usize| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{n}{suffix}"));
+            PathBuf::from(name)
+        };
+
+        // Drop the oldest, then shift the rest up by one.
+        match fs::remove_file(numbered(self.keep)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        for n in (1..self.keep).rev() {
+            let from = numbered(n);
+            if from.exists() {
+                fs::rename(from, numbered(n + 1))?;
+            }
+        }
+
+        if self.compress {
+            compress(path, &numbered(1))?;
+            fs::remove_file(path)?;
+        } else {
+            fs::rename(path, numbered(1))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(from)?);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
 fn open(path: &Path) -> Result<File> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
@@ -61,3 +511,122 @@ fn open(path: &Path) -> Result<File> {
 
     Ok(OpenOptions::new().append(true).create(true).open(path)?)
 }
+
+/// How much of the file `tail` reads at a time, working backwards from the end.
+const TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+
+fn tail(path: &str, count: usize) -> Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut buffer = Vec::new();
+
+    // One more newline than lines wanted guarantees the first line we keep is whole.
+    while end > 0 && buffer.iter().filter(|&&byte| byte == b'\n').count() <= count {
+        let start = end.saturating_sub(TAIL_CHUNK_SIZE);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut buffer);
+        buffer = chunk;
+        end = start;
+    }
+
+    let text = String::from_utf8_lossy(&buffer);
+    let lines: Vec<&str> = text.lines().collect();
+    Ok(lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct GrepOptions {
+    /// Treat the pattern as a regular expression rather than a plain substring.
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    regex: bool,
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    case_insensitive: bool,
+    /// First line to search, counting from 1.
+    #[serde(default)]
+    start_line: Option<usize>,
+    /// Last line to search, inclusive.
+    #[serde(default)]
+    end_line: Option<usize>,
+    #[serde(default = "default_max_results")]
+    max_results: usize,
+}
+
+fn default_max_results() -> usize {
+    1000
+}
+
+impl GrepOptions {
+    fn parse(options: &str) -> Result<Self> {
+        Ok(serde_json::from_str(match options {
+            "" | "null" => "{}",
+            options => options,
+        })?)
+    }
+
+    fn matcher(&self, pattern: &str) -> Result<Regex> {
+        let pattern = if self.regex {
+            pattern.into()
+        } else {
+            regex::escape(pattern)
+        };
+        Ok(RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .build()?)
+    }
+}
+
+#[derive(Serialize)]
+struct GrepMatch {
+    line: usize,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct GrepResult {
+    matches: Vec<GrepMatch>,
+    /// Whether the search stopped early because it hit `max_results`.
+    truncated: bool,
+}
+
+fn grep(path: &str, pattern: &str, options: &GrepOptions) -> Result<GrepResult> {
+    let matcher = options.matcher(pattern)?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut result = GrepResult {
+        matches: Vec::new(),
+        truncated: false,
+    };
+
+    let start = options.start_line.unwrap_or(1);
+    let end = options.end_line.unwrap_or(usize::MAX);
+    let mut buffer = Vec::new();
+
+    for number in 1..=end {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+        if number < start {
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&buffer);
+        let text = text.trim_end_matches(['\n', '\r']);
+        if matcher.is_match(text) {
+            if result.matches.len() >= options.max_results {
+                result.truncated = true;
+                break;
+            }
+            result.matches.push(GrepMatch {
+                line: number,
+                text: text.to_owned(),
+            });
+        }
+    }
+
+    Ok(result)
+}
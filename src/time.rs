@@ -1,10 +1,22 @@
+use crate::{
+    error::{Error, Result},
+    zone::Zone,
+};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use croner::Cron;
+use hdrhistogram::Histogram;
+use serde::Serialize;
 use std::{
     cell::RefCell,
-    collections::hash_map::{Entry, HashMap},
+    collections::{
+        hash_map::{Entry, HashMap},
+        BTreeMap,
+    },
     time::Instant,
 };
 
 thread_local!( static INSTANTS: RefCell<HashMap<String, Instant>> = RefCell::new(HashMap::new()) );
+thread_local!( static PROFILER: RefCell<Profiler> = RefCell::new(Profiler::default()) );
 
 byond_fn!(fn time_microseconds(instant_id) {
     INSTANTS.with(|instants| {
@@ -47,3 +59,269 @@ byond_fn!(
         ))
     }
 );
+
+// Opens a profiling span. Spans opened while another is open are nested under it.
+byond_fn!(fn time_span_begin(name) {
+    PROFILER.with(|profiler| profiler.borrow_mut().begin(name));
+    Some("")
+});
+
+// Closes the innermost span with this name, along with anything left open inside it.
+// Returns the span's duration in microseconds.
+byond_fn!(fn time_span_end(name) {
+    PROFILER.with(|profiler| profiler.borrow_mut().end(name))
+        .map(|micros| micros.to_string())
+});
+
+byond_fn!(fn time_span_dump(reset) {
+    PROFILER.with(|profiler| {
+        let mut profiler = profiler.borrow_mut();
+        let dump = profiler.dump();
+        if reset == "true" {
+            profiler.stats.clear();
+        }
+        dump
+    })
+});
+
+byond_fn!(
+    fn time_span_reset() {
+        PROFILER.with(|profiler| *profiler.borrow_mut() = Profiler::default());
+        Some("")
+    }
+);
+
+/// Spans longer than an hour are clamped, which keeps each histogram small.
+const SPAN_MAX_MICROS: u64 = 60 * 60 * 1_000_000;
+
+#[derive(Default)]
+struct Profiler {
+    /// Currently open spans, innermost last.
+    stack: Vec<(String, Instant)>,
+    /// Recorded durations in microseconds, keyed by the `/`-joined path of span names.
+    stats: BTreeMap<String, Histogram<u64>>,
+}
+
+#[derive(Serialize)]
+struct SpanSummary {
+    count: u64,
+    min: u64,
+    max: u64,
+    mean: f64,
+    p95: u64,
+}
+
+impl Profiler {
+    fn begin(&mut self, name: &str) {
+        self.stack.push((name.to_owned(), Instant::now()));
+    }
+
+    fn end(&mut self, name: &str) -> Option<u64> {
+        let position = self.stack.iter().rposition(|(open, _)| open == name)?;
+        let mut micros = None;
+
+        while self.stack.len() > position {
+            let path = self.path();
+            let (_, started) = self.stack.pop()?;
+            let elapsed = (started.elapsed().as_micros() as u64).min(SPAN_MAX_MICROS);
+
+            self.stats
+                .entry(path)
+                .or_insert_with(|| Histogram::new_with_bounds(1, SPAN_MAX_MICROS, 3).unwrap())
+                .saturating_record(elapsed);
+            micros = Some(elapsed);
+        }
+
+        micros
+    }
+
+    fn path(&self) -> String {
+        self.stack
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn dump(&self) -> Option<String> {
+        let summaries: BTreeMap<&str, SpanSummary> = self
+            .stats
+            .iter()
+            .map(|(path, histogram)| {
+                (
+                    path.as_str(),
+                    SpanSummary {
+                        count: histogram.len(),
+                        min: histogram.min(),
+                        max: histogram.max(),
+                        mean: histogram.mean(),
+                        p95: histogram.value_at_quantile(0.95),
+                    },
+                )
+            })
+            .collect();
+
+        serde_json::to_string(&summaries).ok()
+    }
+}
+
+// Formats a unix timestamp. An empty format gives RFC 3339, an empty timezone means UTC.
+byond_fn!(fn time_format(timestamp, format, timezone) {
+    match format_timestamp(timestamp, format, timezone) {
+        Ok(value) => Some(value),
+        Err(error) => Some(format!("ERROR: {error}"))
+    }
+});
+
+// Parses a date string back into a unix timestamp. Strings without an offset are read in `timezone`.
+byond_fn!(fn time_parse(text, format, timezone) {
+    match parse_timestamp(text, format, timezone) {
+        Ok(value) => Some(value),
+        Err(error) => Some(format!("ERROR: {error}"))
+    }
+});
+
+byond_fn!(fn time_diff(from, to) {
+    match diff_timestamps(from, to) {
+        Ok(value) => Some(value),
+        Err(error) => Some(format!("ERROR: {error}"))
+    }
+});
+
+byond_fn!(fn time_cron_validate(expression) {
+    match parse_cron(expression) {
+        Ok(_) => Some("true".to_owned()),
+        Err(error) => Some(format!("ERROR: {error}"))
+    }
+});
+
+// Returns a JSON list of the next `count` fire times strictly after `after`, as timestamp strings.
+byond_fn!(fn time_cron_next(expression, after, count, timezone) {
+    match cron_next(expression, after, count, timezone) {
+        Ok(value) => Some(value),
+        Err(error) => Some(format!("ERROR: {error}"))
+    }
+});
+
+impl Zone {
+    fn upcoming(&self, cron: &Cron, after: DateTime<Utc>, count: usize) -> Vec<String> {
+        fn collect<Z: TimeZone>(cron: &Cron, after: DateTime<Z>, count: usize) -> Vec<String> {
+            cron.iter_after(after)
+                .take(count)
+                .map(|time| to_unix(&time.to_utc()))
+                .collect()
+        }
+
+        match self {
+            Self::Utc => collect(cron, after, count),
+            Self::Local => collect(cron, after.with_timezone(&Local), count),
+            Self::Named(tz) => collect(cron, after.with_timezone(tz), count),
+        }
+    }
+
+    fn localize(&self, naive: &NaiveDateTime) -> Result<DateTime<Utc>> {
+        let localized = match self {
+            Self::Utc => Some(naive.and_utc()),
+            Self::Local => Local
+                .from_local_datetime(naive)
+                .earliest()
+                .map(|time| time.to_utc()),
+            Self::Named(tz) => tz
+                .from_local_datetime(naive)
+                .earliest()
+                .map(|time| time.to_utc()),
+        };
+        localized.ok_or(Error::InvalidTimestamp)
+    }
+}
+
+fn parse_unix(timestamp: &str) -> Result<DateTime<Utc>> {
+    let seconds: f64 = timestamp.trim().parse()?;
+    DateTime::from_timestamp_micros((seconds * 1_000_000.0).round() as i64)
+        .ok_or(Error::InvalidTimestamp)
+}
+
+fn to_unix(time: &DateTime<Utc>) -> String {
+    format!("{:.6}", time.timestamp_micros() as f64 / 1_000_000.0)
+}
+
+fn format_timestamp(timestamp: &str, format: &str, timezone: &str) -> Result<String> {
+    Zone::parse(timezone)?.format(&parse_unix(timestamp)?, format)
+}
+
+fn parse_timestamp(text: &str, format: &str, timezone: &str) -> Result<String> {
+    let zone = Zone::parse(timezone)?;
+    let text = text.trim();
+
+    let time = if format.is_empty() {
+        match DateTime::parse_from_rfc3339(text) {
+            Ok(time) => time.to_utc(),
+            Err(_) => DateTime::parse_from_rfc2822(text)?.to_utc(),
+        }
+    } else if let Ok(time) = DateTime::parse_from_str(text, format) {
+        time.to_utc()
+    } else {
+        // No offset in the format, so fall back to the requested timezone.
+        let naive = match NaiveDateTime::parse_from_str(text, format) {
+            Ok(naive) => naive,
+            Err(error) => NaiveDate::parse_from_str(text, format)
+                .map(|date| date.and_time(NaiveTime::MIN))
+                .map_err(|_| error)?,
+        };
+        zone.localize(&naive)?
+    };
+
+    Ok(to_unix(&time))
+}
+
+/// The most fire times `time_cron_next` will compute in one call.
+const CRON_MAX_COUNT: usize = 1000;
+
+/// Accepts standard five-field expressions, or six fields with leading seconds.
+fn parse_cron(expression: &str) -> Result<Cron> {
+    Ok(Cron::new(expression).with_seconds_optional().parse()?)
+}
+
+fn cron_next(expression: &str, after: &str, count: &str, timezone: &str) -> Result<String> {
+    let cron = parse_cron(expression)?;
+    let zone = Zone::parse(timezone)?;
+    let after = if after.is_empty() {
+        Utc::now()
+    } else {
+        parse_unix(after)?
+    };
+    let count = if count.is_empty() {
+        1
+    } else {
+        count.parse::<usize>()?.min(CRON_MAX_COUNT)
+    };
+
+    Ok(serde_json::to_string(&zone.upcoming(&cron, after, count))?)
+}
+
+fn diff_timestamps(from: &str, to: &str) -> Result<String> {
+    let delta = parse_unix(to)? - parse_unix(from)?;
+    let negative = delta < chrono::Duration::zero();
+    let total = delta.abs();
+
+    let days = total.num_days();
+    let hours = total.num_hours() % 24;
+    let minutes = total.num_minutes() % 60;
+    let seconds = total.num_seconds() % 60;
+    let milliseconds = total.num_milliseconds() % 1000;
+
+    Ok(serde_json::json!({
+        "total_seconds": delta.num_milliseconds() as f64 / 1000.0,
+        "negative": negative,
+        "days": days,
+        "hours": hours,
+        "minutes": minutes,
+        "seconds": seconds,
+        "milliseconds": milliseconds,
+        "iso": format!(
+            "{}P{days}DT{hours}H{minutes}M{seconds}.{milliseconds:03}S",
+            if negative { "-" } else { "" }
+        ),
+    })
+    .to_string())
+}
@@ -1,4 +1,6 @@
-use crate::error::Result;
+use crate::error::{respond, Error, Result};
+use serde::Serialize;
+use toml_edit::{ser::ValueSerializer, DocumentMut, Item, Key, Table};
 
 byond_fn!(fn toml_file_to_json(path) {
     serde_json::to_string(
@@ -14,11 +16,17 @@ byond_fn!(fn toml_file_to_json(path) {
 });
 
 fn toml_file_to_json_impl(path: &str) -> Result<String> {
+    toml_decode_impl(&std::fs::read_to_string(path)?)
+}
+
+byond_fn!(fn toml_decode(text) {
+    respond(toml_decode_impl(text))
+});
+
+fn toml_decode_impl(text: &str) -> Result<String> {
     Ok(serde_json::to_string(&toml_dep::from_str::<
         toml_dep::Value,
-    >(&std::fs::read_to_string(
-        path,
-    )?)?)?)
+    >(text)?)?)
 }
 
 byond_fn!(fn toml_encode(value) {
@@ -40,3 +48,88 @@ fn toml_encode_impl(value: &str) -> Result<String> {
         toml_dep::Value,
     >(value)?)?)
 }
+
+// The edit functions below go through `toml_edit`, so comments, ordering and
+// formatting of everything they don't touch are kept as they were.
+
+byond_fn!(fn toml_set(text, key, value) {
+    respond(toml_set_impl(text, key, value))
+});
+
+byond_fn!(fn toml_remove(text, key) {
+    respond(toml_remove_impl(text, key))
+});
+
+byond_fn!(fn toml_file_set(path, key, value) {
+    respond(edit_file(path, |text| toml_set_impl(text, key, value)))
+});
+
+byond_fn!(fn toml_file_remove(path, key) {
+    respond(edit_file(path, |text| toml_remove_impl(text, key)))
+});
+
+fn edit_file(path: &str, edit: impl FnOnce(&str) -> Result<String>) -> Result<String> {
+    let edited = edit(&std::fs::read_to_string(path)?)?;
+    std::fs::write(path, &edited)?;
+    Ok(edited)
+}
+
+/// Sets the dotted `key` (quoted parts are allowed, as in TOML itself) to the JSON `value`,
+/// creating any missing tables on the way.
+fn toml_set_impl(text: &str, key: &str, value: &str) -> Result<String> {
+    let mut document: DocumentMut = text.parse()?;
+    let mut value =
+        serde_json::from_str::<serde_json::Value>(value)?.serialize(ValueSerializer::new())?;
+    let (last, parents) = split_key(key)?;
+
+    let mut table = document.as_table_mut() as &mut dyn toml_edit::TableLike;
+    for part in parents {
+        let mut implicit = Table::new();
+        implicit.set_implicit(true);
+        table = table
+            .entry(part.get())
+            .or_insert(Item::Table(implicit))
+            .as_table_like_mut()
+            .ok_or_else(|| Error::InvalidTomlKey(key.to_owned()))?;
+    }
+
+    match table.get_mut(last.get()) {
+        Some(Item::Value(existing)) => {
+            // Keep whatever comments and spacing surrounded the old value.
+            *value.decor_mut() = existing.decor().clone();
+            *existing = value;
+        }
+        Some(existing) => *existing = Item::Value(value),
+        None => {
+            table.insert(last.get(), Item::Value(value));
+        }
+    }
+
+    Ok(document.to_string())
+}
+
+fn toml_remove_impl(text: &str, key: &str) -> Result<String> {
+    let mut document: DocumentMut = text.parse()?;
+    let (last, parents) = split_key(key)?;
+
+    let mut table = Some(document.as_table_mut() as &mut dyn toml_edit::TableLike);
+    for part in parents {
+        table = table
+            .and_then(|table| table.get_mut(part.get()))
+            .and_then(Item::as_table_like_mut);
+    }
+
+    if let Some(table) = table {
+        table.remove(last.get());
+    }
+
+    Ok(document.to_string())
+}
+
+fn split_key(key: &str) -> Result<(Key, Vec<Key>)> {
+    let mut parts = Key::parse(key)?;
+    let last = parts
+        .pop()
+        .ok_or_else(|| Error::InvalidTomlKey(key.to_owned()))?;
+    Ok((last, parts))
+}
@@ -1,63 +1,684 @@
-use crate::{error::Result, http::HTTP_CLIENT, jobs};
+use crate::{
+    error::{respond, Error, Result},
+    hash::{bytes_hash, file_hash},
+    http::HTTP_CLIENT,
+    jobs,
+};
+use base64::Engine;
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
 use reqwest::blocking::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use std::path::Path;
-use zip::ZipArchive;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+thread_local!( static DOWNLOADS: RefCell<HashMap<String, Arc<Progress>>> = RefCell::new(HashMap::new()) );
 
 struct UnzipPrep {
     req: RequestBuilder,
     unzip_directory: String,
+    options: String,
+    progress: Arc<Progress>,
+}
+
+/// Shared between a download job and the BYOND thread asking how far along it is.
+#[derive(Default)]
+struct Progress {
+    downloaded: AtomicU64,
+    /// Zero until the server says how big the download is, if it ever does.
+    total: AtomicU64,
 }
 
-fn construct_unzip(url: &str, unzip_directory: &str) -> UnzipPrep {
+fn construct_unzip(url: &str, unzip_directory: &str, options: &str) -> UnzipPrep {
     let req = HTTP_CLIENT.get(url);
     let dir_copy = unzip_directory.to_string();
 
     UnzipPrep {
         req,
         unzip_directory: dir_copy,
+        options: options.to_string(),
+        progress: Arc::default(),
     }
 }
 
-byond_fn!(fn unzip_download_async(url, unzip_directory) {
-    let unzip = construct_unzip(url, unzip_directory);
-    Some(jobs::start(move ||
+// Every extraction function takes optional JSON options (see `ExtractOptions`), and on success
+// returns a JSON manifest of what was written.
+byond_fn!(fn unzip_download_async(url, unzip_directory, ...rest) {
+    let unzip = construct_unzip(url, unzip_directory, rest.first().map_or("", |x| &**x));
+    let progress = unzip.progress.clone();
+    let id = jobs::start(move ||
         do_unzip_download(unzip).unwrap_or_else(|e| e.to_string())
-    ))
+    );
+    DOWNLOADS.with(|downloads| downloads.borrow_mut().insert(id.clone(), progress));
+    Some(id)
+});
+
+// Returns how much of a running `unzip_download_async` job has downloaded, or null once it is done.
+byond_fn!(fn unzip_progress(id) {
+    DOWNLOADS.with(|downloads| {
+        let progress = downloads.borrow().get(id).map(|progress| {
+            let total = progress.total.load(Ordering::Relaxed);
+            serde_json::json!({
+                "downloaded": progress.downloaded.load(Ordering::Relaxed),
+                "total": (total > 0).then_some(total),
+            })
+        });
+        serde_json::to_string(&progress).ok()
+    })
 });
 
 fn do_unzip_download(prep: UnzipPrep) -> Result<String> {
-    let unzip_path = Path::new(&prep.unzip_directory);
-    let response = prep.req.send()?;
+    let mut extractor = Extractor::new(Path::new(&prep.unzip_directory), &prep.options)?;
+    let mut response = prep.req.send()?;
 
-    let content = response.bytes()?;
+    let total = response.content_length().unwrap_or(0);
+    prep.progress.total.store(total, Ordering::Relaxed);
 
-    let reader = std::io::Cursor::new(content);
-    let mut archive = ZipArchive::new(reader)?;
+    // The archive itself is held in memory, so it can't be bigger than what it may extract to either.
+    // Content-Length is only a claim, so what actually arrives is counted too.
+    let max_size = extractor.options.max_size;
+    let over_limit = || Error::ArchiveLimit(format!("{max_size} bytes"));
+    if total > max_size {
+        return Err(over_limit());
+    }
 
-    for i in 0..archive.len() {
-        let mut entry = archive.by_index(i)?;
+    let mut content = Vec::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = response.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        if (content.len() + read) as u64 > max_size {
+            return Err(over_limit());
+        }
+        content.extend_from_slice(&buffer[..read]);
+        prep.progress
+            .downloaded
+            .fetch_add(read as u64, Ordering::Relaxed);
+    }
+
+    extractor.verify(|| bytes_hash("sha256", &content))?;
+
+    let reader = io::Cursor::new(content);
+    extractor.extract_zip(ZipArchive::new(reader)?)?;
+
+    extractor.manifest()
+}
+
+byond_fn!(fn unzip_file_async(path, unzip_directory, ...rest) {
+    let path = path.to_owned();
+    let unzip_directory = unzip_directory.to_owned();
+    let options = rest.first().map_or("", |x| &**x).to_owned();
+    Some(jobs::start(move ||
+        do_unzip_file(&path, &unzip_directory, &options).unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+fn do_unzip_file(path: &str, unzip_directory: &str, options: &str) -> Result<String> {
+    let mut extractor = Extractor::new(Path::new(unzip_directory), options)?;
+    extractor.verify(|| file_hash("sha256", path))?;
+    let file = BufReader::new(fs::File::open(path)?);
+    extractor.extract_zip(ZipArchive::new(file)?)?;
+
+    extractor.manifest()
+}
 
-        let file_path = unzip_path.join(entry.name());
+/// Archives are untrusted input, so these limits apply unless the caller raises them.
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct ExtractOptions {
+    /// The most bytes all entries together may decompress to.
+    max_size: u64,
+    /// The most entries, directories included, an archive may have.
+    max_entries: usize,
+    /// Hex sha256 the archive must have. Checked before anything is extracted.
+    sha256: Option<String>,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            sha256: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    name: String,
+    size: u64,
+    is_dir: bool,
+}
+
+/// Writes archive entries under a root directory, whatever the archive format.
+/// Extraction stops at the first entry that is unsafe or over the limits;
+/// anything written before it is left in place.
+struct Extractor {
+    root: PathBuf,
+    options: ExtractOptions,
+    total_size: u64,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Extractor {
+    fn new(root: &Path, options: &str) -> Result<Self> {
+        let options = match options {
+            "" | "null" => ExtractOptions::default(),
+            options => serde_json::from_str(options)?,
+        };
+
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.canonicalize()?,
+            options,
+            total_size: 0,
+            entries: Vec::new(),
+        })
+    }
+
+    fn verify(&self, hash: impl FnOnce() -> Result<String>) -> Result<()> {
+        let Some(expected) = &self.options.sha256 else {
+            return Ok(());
+        };
+
+        let actual = hash()?;
+        if actual.eq_ignore_ascii_case(expected.trim()) {
+            Ok(())
+        } else {
+            Err(Error::ChecksumMismatch {
+                expected: expected.clone(),
+                actual,
+            })
+        }
+    }
+
+    fn extract_zip<R: Read + Seek>(&mut self, mut archive: ZipArchive<R>) -> Result<()> {
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let name = entry.name().to_owned();
+            if entry
+                .unix_mode()
+                .is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+            {
+                return Err(Error::UnsafeArchiveEntry(name, "it is a symlink"));
+            }
+            let is_dir = entry.is_dir();
+            self.extract(&name, is_dir, &mut entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn extract(&mut self, name: &str, is_dir: bool, reader: &mut impl Read) -> Result<()> {
+        if self.entries.len() >= self.options.max_entries {
+            return Err(Error::ArchiveLimit(format!(
+                "{} entries",
+                self.options.max_entries
+            )));
+        }
+
+        let file_path = self.resolve(name)?;
+
+        if file_path == self.root {
+            // Tarballs made with `tar -C dir .` start with a `./` entry for the root itself.
+            return match is_dir {
+                true => Ok(()),
+                false => Err(Error::UnsafeArchiveEntry(name.to_owned(), "it has no name")),
+            };
+        }
+
+        if is_dir {
+            self.create_dir(&file_path, name)?;
+            self.entries.push(ManifestEntry {
+                name: name.to_owned(),
+                size: 0,
+                is_dir,
+            });
+            return Ok(());
+        }
 
         if let Some(parent) = file_path.parent() {
-            fs::create_dir_all(parent)?
+            self.create_dir(parent, name)?;
+        }
+        if fs::symlink_metadata(&file_path).is_ok_and(|metadata| metadata.is_symlink()) {
+            return Err(Error::UnsafeArchiveEntry(
+                name.to_owned(),
+                "it would be written through an existing symlink",
+            ));
         }
 
         let file = fs::OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&file_path)?;
 
-        let mut writer = std::io::BufWriter::new(file);
-        std::io::copy(&mut entry, &mut writer)?;
+        // Sizes in archive headers can lie, so count what actually comes out.
+        let remaining = self.options.max_size - self.total_size;
+        let mut writer = BufWriter::new(file);
+        let size = io::copy(&mut reader.take(remaining.saturating_add(1)), &mut writer)?;
         writer.flush()?;
+
+        if size > remaining {
+            drop(writer);
+            let _ = fs::remove_file(&file_path);
+            return Err(Error::ArchiveLimit(format!(
+                "{} bytes",
+                self.options.max_size
+            )));
+        }
+
+        self.total_size += size;
+        self.entries.push(ManifestEntry {
+            name: name.to_owned(),
+            size,
+            is_dir,
+        });
+
+        Ok(())
+    }
+
+    /// Joins an entry name onto the root, refusing anything that would land outside it.
+    fn resolve(&self, name: &str) -> Result<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(Error::UnsafeArchiveEntry(
+                        name.to_owned(),
+                        "it leads outside the target directory",
+                    ))
+                }
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Creates `directory` and any missing parents, unless that would create them outside the root.
+    fn create_dir(&self, directory: &Path, name: &str) -> Result<()> {
+        // An earlier entry or something already on disk may have made part of the path a symlink,
+        // so check where the deepest part that already exists really is before adding to it.
+        let existing = directory
+            .ancestors()
+            .find(|ancestor| fs::symlink_metadata(ancestor).is_ok())
+            .unwrap_or(&self.root);
+        self.check_inside(existing, name)?;
+        fs::create_dir_all(directory)?;
+        Ok(())
+    }
+
+    fn check_inside(&self, directory: &Path, name: &str) -> Result<()> {
+        if directory.canonicalize()?.starts_with(&self.root) {
+            Ok(())
+        } else {
+            Err(Error::UnsafeArchiveEntry(
+                name.to_owned(),
+                "it leads outside the target directory",
+            ))
+        }
+    }
+
+    fn manifest(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.entries)?)
+    }
+}
+
+/// The name a file or directory passed to one of the archive creation functions is stored under.
+fn root_name(path: &Path) -> Result<String> {
+    Ok(path
+        .file_name()
+        .ok_or(Error::InvalidFilename)?
+        .to_string_lossy()
+        .into_owned())
+}
+
+// Zips up a JSON list of files and directories. Each one is stored under its own name,
+// so `data/logs/round-5` ends up as `round-5/...` in the archive.
+byond_fn!(fn zip_create_async(paths, output_path) {
+    let paths = paths.to_owned();
+    let output_path = output_path.to_owned();
+    Some(jobs::start(move ||
+        do_zip_create(&paths, &output_path).unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+fn do_zip_create(paths: &str, output_path: &str) -> Result<String> {
+    let paths: Vec<String> = serde_json::from_str(paths)?;
+    let mut writer = ZipWriter::new(BufWriter::new(fs::File::create(output_path)?));
+
+    for path in &paths {
+        let path = Path::new(path);
+        add_to_zip(&mut writer, path, &root_name(path)?)?;
+    }
+
+    writer.finish()?.flush()?;
+
+    Ok("true".to_string())
+}
+
+fn add_to_zip<W: Write + Seek>(writer: &mut ZipWriter<W>, path: &Path, name: &str) -> Result<()> {
+    let options = SimpleFileOptions::default();
+
+    if path.is_dir() {
+        writer.add_directory(format!("{name}/"), options)?;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let child = format!("{name}/{}", entry.file_name().to_string_lossy());
+            add_to_zip(writer, &entry.path(), &child)?;
+        }
+    } else {
+        writer.start_file(name, options)?;
+        io::copy(&mut fs::File::open(path)?, writer)?;
+    }
+
+    Ok(())
+}
+
+// Returns a JSON list of the entries in a zip file.
+byond_fn!(fn zip_list_async(path) {
+    let path = path.to_owned();
+    Some(jobs::start(move ||
+        do_zip_list(&path).unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+#[derive(Serialize)]
+struct ZipEntry {
+    name: String,
+    size: u64,
+    compressed_size: u64,
+    is_dir: bool,
+}
+
+fn do_zip_list(path: &str) -> Result<String> {
+    let mut archive = ZipArchive::new(BufReader::new(fs::File::open(path)?))?;
+    let mut entries = Vec::with_capacity(archive.len());
+
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        entries.push(ZipEntry {
+            name: entry.name().to_owned(),
+            size: entry.size(),
+            compressed_size: entry.compressed_size(),
+            is_dir: entry.is_dir(),
+        });
     }
 
+    Ok(serde_json::to_string(&entries)?)
+}
+
+// Extracts a tarball, which may be plain, gzip or zstd compressed.
+byond_fn!(fn tar_extract_async(path, unzip_directory, ...rest) {
+    let path = path.to_owned();
+    let unzip_directory = unzip_directory.to_owned();
+    let options = rest.first().map_or("", |x| &**x).to_owned();
+    Some(jobs::start(move ||
+        do_tar_extract(&path, &unzip_directory, &options).unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+// Like `zip_create_async`, compressing the tarball according to the extension of `output_path`
+// (`.tar.gz`/`.tgz` or `.tar.zst`/`.tzst`).
+byond_fn!(fn tar_create_async(paths, output_path) {
+    let paths = paths.to_owned();
+    let output_path = output_path.to_owned();
+    Some(jobs::start(move ||
+        do_tar_create(&paths, &output_path).unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+byond_fn!(fn compress_file_async(format, path, output_path) {
+    let format = format.to_owned();
+    let path = path.to_owned();
+    let output_path = output_path.to_owned();
+    Some(jobs::start(move ||
+        do_compress_file(&format, &path, &output_path).unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+// Decompression takes an optional `max_size` in bytes, defaulting to the same 1 GiB as extraction.
+byond_fn!(fn decompress_file_async(format, path, output_path, ...rest) {
+    let format = format.to_owned();
+    let path = path.to_owned();
+    let output_path = output_path.to_owned();
+    let max_size = rest.first().map_or("", |x| &**x).to_owned();
+    Some(jobs::start(move ||
+        parse_max_size(&max_size)
+            .and_then(|max_size| do_decompress_file(&format, &path, &output_path, max_size))
+            .unwrap_or_else(|e| e.to_string())
+    ))
+});
+
+// Compresses `text` and returns it as base64, since DM strings can't hold binary data.
+byond_fn!(fn compress_string(format, text) {
+    respond(compress_string_impl(format, text))
+});
+
+byond_fn!(fn decompress_string(format, data, ...rest) {
+    let max_size = rest.first().map_or("", |x| &**x);
+    respond(
+        parse_max_size(max_size).and_then(|max_size| decompress_string_impl(format, data, max_size))
+    )
+});
+
+#[derive(Clone, Copy)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn parse(format: &str) -> Result<Self> {
+        match format {
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(Error::InvalidCompression(format.to_owned())),
+        }
+    }
+
+    fn from_extension(path: &str) -> Self {
+        if path.ends_with(".gz") || path.ends_with(".tgz") {
+            Self::Gzip
+        } else if path.ends_with(".zst") || path.ends_with(".tzst") {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Sniffs the magic bytes at the start of `reader`.
+    fn detect(reader: &mut impl BufRead) -> io::Result<Self> {
+        let magic = reader.fill_buf()?;
+        Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else {
+            Self::None
+        })
+    }
+
+    fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+
+    fn encoder<W: Write>(self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Self::None => Encoder::None(writer),
+            Self::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Self::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+}
+
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes out the compression trailer, which dropping the encoder would skip.
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(writer) => Ok(writer),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn do_tar_extract(path: &str, unzip_directory: &str, options: &str) -> Result<String> {
+    let mut extractor = Extractor::new(Path::new(unzip_directory), options)?;
+    extractor.verify(|| file_hash("sha256", path))?;
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let compression = Compression::detect(&mut reader)?;
+    let mut archive = tar::Archive::new(compression.decoder(reader)?);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let is_dir = match entry.header().entry_type() {
+            tar::EntryType::Directory => true,
+            tar::EntryType::Regular | tar::EntryType::Continuous => false,
+            tar::EntryType::Symlink | tar::EntryType::Link => {
+                return Err(Error::UnsafeArchiveEntry(name, "it is a link"))
+            }
+            // Devices and metadata-only entries have nothing to write out.
+            _ => continue,
+        };
+        extractor.extract(&name, is_dir, &mut entry)?;
+    }
+
+    extractor.manifest()
+}
+
+fn do_tar_create(paths: &str, output_path: &str) -> Result<String> {
+    let paths: Vec<String> = serde_json::from_str(paths)?;
+    let compression = Compression::from_extension(output_path);
+    let writer = BufWriter::new(fs::File::create(output_path)?);
+    let mut builder = tar::Builder::new(compression.encoder(writer)?);
+
+    for path in &paths {
+        let path = Path::new(path);
+        let name = root_name(path)?;
+        if path.is_dir() {
+            builder.append_dir_all(&name, path)?;
+        } else {
+            builder.append_path_with_name(path, &name)?;
+        }
+    }
+
+    builder.into_inner()?.finish()?.flush()?;
+
+    Ok("true".to_string())
+}
+
+fn do_compress_file(format: &str, path: &str, output_path: &str) -> Result<String> {
+    let compression = Compression::parse(format)?;
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut encoder = compression.encoder(BufWriter::new(fs::File::create(output_path)?))?;
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+
+    Ok("true".to_string())
+}
+
+fn parse_max_size(max_size: &str) -> Result<u64> {
+    match max_size {
+        "" | "null" => Ok(DEFAULT_MAX_SIZE),
+        max_size => Ok(max_size.parse()?),
+    }
+}
+
+/// Copies everything `decoder` produces into `writer`, failing once it passes `max_size` bytes.
+fn decompress_limited(decoder: impl Read, writer: &mut impl Write, max_size: u64) -> Result<()> {
+    let size = io::copy(&mut decoder.take(max_size.saturating_add(1)), writer)?;
+    if size > max_size {
+        return Err(Error::ArchiveLimit(format!("{max_size} bytes")));
+    }
+    Ok(())
+}
+
+fn do_decompress_file(
+    format: &str,
+    path: &str,
+    output_path: &str,
+    max_size: u64,
+) -> Result<String> {
+    let compression = Compression::parse(format)?;
+    let decoder = compression.decoder(BufReader::new(fs::File::open(path)?))?;
+    let mut writer = BufWriter::new(fs::File::create(output_path)?);
+    if let Err(e) = decompress_limited(decoder, &mut writer, max_size) {
+        drop(writer);
+        let _ = fs::remove_file(output_path);
+        return Err(e);
+    }
+    writer.flush()?;
+
     Ok("true".to_string())
 }
 
+fn compress_string_impl(format: &str, text: &str) -> Result<String> {
+    let mut encoder = Compression::parse(format)?.encoder(Vec::new())?;
+    encoder.write_all(text.as_bytes())?;
+    Ok(base64::prelude::BASE64_STANDARD.encode(encoder.finish()?))
+}
+
+fn decompress_string_impl(format: &str, data: &str, max_size: u64) -> Result<String> {
+    let compressed = base64::prelude::BASE64_STANDARD.decode(data)?;
+    let decoder = Compression::parse(format)?.decoder(compressed.as_slice())?;
+    let mut text = Vec::new();
+    decompress_limited(decoder, &mut text, max_size)?;
+    Ok(String::from_utf8(text).map_err(|e| e.utf8_error())?)
+}
+
 byond_fn!(fn unzip_check(id) {
-    Some(jobs::check(id))
+    let result = jobs::check(id);
+    if result != jobs::NO_RESULTS_YET {
+        DOWNLOADS.with(|downloads| downloads.borrow_mut().remove(id));
+    }
+    Some(result)
 });
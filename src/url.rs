@@ -1,13 +1,73 @@
-use crate::error::Result;
+use crate::error::{respond, Error, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use url_dep::form_urlencoded::byte_serialize;
+use url_dep::{form_urlencoded::byte_serialize, Url};
 
-byond_fn!(fn url_encode(data) {
-    Some(encode(data))
+/// Everything except the RFC 3986 unreserved characters, like `encodeURIComponent`.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Like `COMPONENT`, but leaves the sub-delimiters, `:` and `@` that RFC 3986
+/// allows in a single path segment. `/` is still encoded.
+const PATH_SEGMENT: &AsciiSet = &COMPONENT
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b'+')
+    .remove(b',')
+    .remove(b';')
+    .remove(b'=')
+    .remove(b':')
+    .remove(b'@');
+
+// The mode is optional and defaults to "form", as BYOND's builtins do.
+byond_fn!(fn url_encode(data, ...rest) {
+    match rest.first().map(|x| &**x) {
+        None | Some("") | Some("form") => Some(encode(data)),
+        Some("component") => Some(utf8_percent_encode(data, COMPONENT).to_string()),
+        Some("path") => Some(utf8_percent_encode(data, PATH_SEGMENT).to_string()),
+        Some(_) => None,
+    }
 });
 
-byond_fn!(fn url_decode(data) {
-    decode(data).ok()
+byond_fn!(fn url_decode(data, ...rest) {
+    match rest.first().map(|x| &**x) {
+        None | Some("") | Some("form") => decode(data).ok(),
+        Some("component") | Some("path") => Some(
+            percent_encoding::percent_decode_str(data)
+                .decode_utf8_lossy()
+                .into_owned(),
+        ),
+        Some(_) => None,
+    }
+});
+
+byond_fn!(fn html_encode(data) {
+    Some(html_escape::encode_safe(data).into_owned())
+});
+
+byond_fn!(fn html_decode(data) {
+    Some(html_escape::decode_html_entities(data).into_owned())
+});
+
+byond_fn!(fn url_parse(url) {
+    respond(parse(url))
+});
+
+byond_fn!(fn url_build(parts) {
+    respond(build(parts))
+});
+
+byond_fn!(fn url_join(base, relative) {
+    respond(join(base, relative))
 });
 
 fn encode(string: &str) -> String {
@@ -39,3 +99,83 @@ fn replace_plus(input: &[u8]) -> Cow<[u8]> {
         }
     }
 }
+
+/// The components of a URL, as handed to and received from DM.
+#[derive(Serialize, Deserialize)]
+struct UrlParts {
+    scheme: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    host: Option<String>,
+    /// The explicit port, or the default port for well-known schemes.
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    path: String,
+    /// The raw query string, without the leading `?`.
+    #[serde(default)]
+    query: Option<String>,
+    /// The decoded query as ordered key/value pairs. Takes precedence over
+    /// `query` when building a URL.
+    #[serde(default)]
+    query_pairs: Vec<(String, String)>,
+    #[serde(default)]
+    fragment: Option<String>,
+}
+
+fn parse(url: &str) -> Result<UrlParts> {
+    let url = Url::parse(url)?;
+
+    Ok(UrlParts {
+        scheme: url.scheme().to_owned(),
+        username: url.username().to_owned(),
+        password: url.password().map(str::to_owned),
+        host: url.host_str().map(str::to_owned),
+        port: url.port_or_known_default(),
+        path: url.path().to_owned(),
+        query: url.query().map(str::to_owned),
+        query_pairs: url.query_pairs().into_owned().collect(),
+        fragment: url.fragment().map(str::to_owned),
+    })
+}
+
+fn build(parts: &str) -> Result<String> {
+    let parts: UrlParts = serde_json::from_str(parts)?;
+
+    let mut url = match &parts.host {
+        Some(host) => Url::parse(&format!("{}://{}", parts.scheme, host))?,
+        None => Url::parse(&format!("{}:", parts.scheme))?,
+    };
+
+    if !parts.username.is_empty() {
+        url.set_username(&parts.username)
+            .map_err(|_| Error::InvalidUrlComponent("username"))?;
+    }
+    if parts.password.is_some() {
+        url.set_password(parts.password.as_deref())
+            .map_err(|_| Error::InvalidUrlComponent("password"))?;
+    }
+    if parts.port.is_some() {
+        url.set_port(parts.port)
+            .map_err(|_| Error::InvalidUrlComponent("port"))?;
+    }
+
+    url.set_path(&parts.path);
+
+    if !parts.query_pairs.is_empty() {
+        url.query_pairs_mut().extend_pairs(&parts.query_pairs);
+    } else {
+        url.set_query(parts.query.as_deref());
+    }
+
+    url.set_fragment(parts.fragment.as_deref());
+
+    Ok(url.into())
+}
+
+fn join(base: &str, relative: &str) -> Result<String> {
+    Ok(Url::parse(base)?.join(relative)?.into())
+}
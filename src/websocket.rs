@@ -0,0 +1,408 @@
+use crate::byond::deserialize_bool;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+use tungstenite::{
+    client::IntoClientRequest,
+    handshake::client::Request,
+    http::{HeaderName, HeaderValue},
+    stream::MaybeTlsStream,
+    Message, WebSocket,
+};
+
+const DEFAULT_RECONNECT_DELAY: f32 = 1.0;
+const DEFAULT_MAX_RECONNECT_DELAY: f32 = 30.0;
+
+/// How long a connection thread blocks reading before checking for messages to send.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many messages may wait to be sent, or to be polled by DM.
+const QUEUE_SIZE: usize = 1000;
+
+thread_local! {
+    static CONNECTIONS: RefCell<HashMap<String, Connection>> = RefCell::new(HashMap::new());
+}
+
+/// DM's end of a connection thread. Dropping it makes the thread close the socket and exit.
+struct Connection {
+    sender: flume::Sender<Message>,
+    receiver: flume::Receiver<Event>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct ConnectOptions {
+    /// Extra headers for the opening handshake, such as `Authorization`.
+    headers: BTreeMap<String, String>,
+    /// Whether to connect again after the connection fails or the server closes it.
+    #[serde(deserialize_with = "deserialize_bool")]
+    reconnect: bool,
+    /// Seconds to wait before the first reconnection attempt. Doubles after each failed attempt.
+    reconnect_delay: f32,
+    /// The longest to wait between reconnection attempts, in seconds.
+    max_reconnect_delay: f32,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            headers: BTreeMap::new(),
+            reconnect: true,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            max_reconnect_delay: DEFAULT_MAX_RECONNECT_DELAY,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+enum Event {
+    Open,
+    Message(String),
+    /// Base64 encoded.
+    Binary(String),
+    /// Why the connection ended, whether the server closed it or it failed.
+    Close(String),
+    /// How many messages were thrown away because DM wasn't polling fast enough.
+    Dropped(usize),
+}
+
+fn client_request(url: &str, headers: &BTreeMap<String, String>) -> Result<Request, String> {
+    let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| e.to_string())?;
+        let value = HeaderValue::from_str(value).map_err(|e| e.to_string())?;
+        request.headers_mut().insert(name, value);
+    }
+    Ok(request)
+}
+
+fn set_read_timeout(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> std::io::Result<()> {
+    match socket.get_mut() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(POLL_INTERVAL)),
+        MaybeTlsStream::Rustls(stream) => stream.get_mut().set_read_timeout(Some(POLL_INTERVAL)),
+        _ => Ok(()),
+    }
+}
+
+/// Tells DM how many messages it missed because its queue was full, once there is room again.
+fn report_dropped(out: &flume::Sender<Event>, dropped: &mut usize) {
+    if *dropped > 0 && out.try_send(Event::Dropped(*dropped)).is_ok() {
+        *dropped = 0;
+    }
+}
+
+/// Runs one connection until it ends. Returns None if DM closed it, or why it was lost.
+fn handle_socket(
+    mut socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    control: &flume::Receiver<Message>,
+    out: &flume::Sender<Event>,
+) -> Option<String> {
+    if let Err(e) = set_read_timeout(&mut socket) {
+        return Some(e.to_string());
+    }
+    let mut dropped = 0;
+
+    loop {
+        loop {
+            match control.try_recv() {
+                Ok(message) => {
+                    if let Err(e) = socket.send(message) {
+                        return Some(e.to_string());
+                    }
+                }
+                Err(flume::TryRecvError::Empty) => break,
+                Err(flume::TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return None;
+                }
+            }
+        }
+
+        let event = match socket.read() {
+            Ok(Message::Text(text)) => Event::Message(text),
+            Ok(Message::Binary(data)) => {
+                Event::Binary(base64::prelude::BASE64_STANDARD.encode(data))
+            }
+            Ok(Message::Close(frame)) => {
+                // Sends the close reply tungstenite queued up.
+                let _ = socket.flush();
+                return Some(match frame {
+                    Some(frame) if !frame.reason.is_empty() => frame.reason.into_owned(),
+                    Some(frame) => format!("Closed by the server with code {}", frame.code),
+                    None => "Closed by the server".to_owned(),
+                });
+            }
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                // Sends any pong replies tungstenite queued up while reading.
+                if let Err(e) = socket.flush() {
+                    return Some(e.to_string());
+                }
+                report_dropped(out, &mut dropped);
+                continue;
+            }
+            Err(e) => return Some(e.to_string()),
+        };
+
+        report_dropped(out, &mut dropped);
+        match out.try_send(event) {
+            Ok(()) => {}
+            Err(flume::TrySendError::Full(_)) => dropped += 1,
+            Err(flume::TrySendError::Disconnected(_)) => {
+                let _ = socket.close(None);
+                let _ = socket.flush();
+                return None; // If no one wants to receive any more messages from us, we exit this thread
+            }
+        }
+    }
+}
+
+/// Sleeps for `delay`, waking early to return false if DM closed the connection meanwhile.
+fn wait_to_reconnect(control: &flume::Receiver<Message>, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline {
+        if control.is_disconnected() {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    !control.is_disconnected()
+}
+
+fn handle_connection(
+    url: String,
+    options: ConnectOptions,
+    control: flume::Receiver<Message>,
+    out: flume::Sender<Event>,
+) {
+    let mut delay = options.reconnect_delay.max(0.0);
+
+    loop {
+        let reason = match client_request(&url, &options.headers)
+            .and_then(|request| tungstenite::connect(request).map_err(|e| e.to_string()))
+        {
+            Ok((socket, _)) => {
+                delay = options.reconnect_delay.max(0.0);
+                if out.send(Event::Open).is_err() {
+                    return;
+                }
+                match handle_socket(socket, &control, &out) {
+                    Some(reason) => reason,
+                    None => return,
+                }
+            }
+            Err(reason) => reason,
+        };
+
+        // Messages sent meanwhile wait in the channel for the next connection.
+        if out.send(Event::Close(reason)).is_err()
+            || !options.reconnect
+            || !wait_to_reconnect(&control, Duration::from_secs_f32(delay))
+        {
+            return;
+        }
+        delay = (delay * 2.0).min(options.max_reconnect_delay.max(0.0));
+    }
+}
+
+fn connect(id: &str, url: &str, options: &str) -> Result<(), String> {
+    // DM's json_encode gives "null" for no options and "[]" for an empty list.
+    let options: ConnectOptions = match options {
+        "" | "null" | "[]" => ConnectOptions::default(),
+        options => serde_json::from_str(options).map_err(|e| e.to_string())?,
+    };
+    // Catch a bad URL, header or delay now rather than from the connection thread.
+    client_request(url, &options.headers)?;
+    for (name, delay) in [
+        ("reconnect_delay", options.reconnect_delay),
+        ("max_reconnect_delay", options.max_reconnect_delay),
+    ] {
+        Duration::try_from_secs_f32(delay.max(0.0))
+            .map_err(|_| format!("Invalid {name}: {delay}"))?;
+    }
+
+    let (c_sender, c_receiver) = flume::bounded(QUEUE_SIZE);
+    let (o_sender, o_receiver) = flume::bounded(QUEUE_SIZE);
+    // Replacing an existing connection drops it, which closes it.
+    CONNECTIONS.with(|connections| {
+        connections.borrow_mut().insert(
+            id.to_owned(),
+            Connection {
+                sender: c_sender,
+                receiver: o_receiver,
+            },
+        )
+    });
+    let url = url.to_owned();
+    thread::spawn(move || handle_connection(url, options, c_receiver, o_sender));
+    Ok(())
+}
+
+fn close(id: &str) {
+    CONNECTIONS.with(|connections| {
+        connections.borrow_mut().remove(id);
+    });
+}
+
+fn send(id: &str, message: Message) -> Option<String> {
+    CONNECTIONS.with(|connections| {
+        if let Some(connection) = connections.borrow().get(id) {
+            connection
+                .sender
+                .try_send(message)
+                .err()
+                .map(|e| e.to_string())
+        } else {
+            Some("Not connected".to_owned())
+        }
+    })
+}
+
+fn poll(id: &str) -> String {
+    let events: Vec<Event> = CONNECTIONS.with(|connections| {
+        connections
+            .borrow()
+            .get(id)
+            .map(|connection| connection.receiver.try_iter().collect())
+            .unwrap_or_default()
+    });
+
+    serde_json::to_string(&events).unwrap_or_else(|_| "[]".to_owned())
+}
+
+byond_fn!(fn websocket_connect(id, url, options) {
+    connect(id, url, options).err()
+});
+
+byond_fn!(fn websocket_close(id) {
+    close(id);
+    Some("")
+});
+
+byond_fn!(fn websocket_send(id, text) {
+    send(id, Message::Text(text.to_owned()))
+});
+
+byond_fn!(fn websocket_send_binary(id, data) {
+    match base64::prelude::BASE64_STANDARD.decode(data) {
+        Ok(bytes) => send(id, Message::Binary(bytes)),
+        Err(e) => Some(e.to_string()),
+    }
+});
+
+byond_fn!(fn websocket_poll(id) {
+    Some(poll(id))
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::net::TcpListener;
+
+    /// Accepts one connection on a local port and hands it to `handler` on its own thread.
+    fn serve(handler: impl FnOnce(WebSocket<TcpStream>) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handler(tungstenite::accept(stream).unwrap());
+        });
+        url
+    }
+
+    /// Polls until `done` is happy with everything received so far, or five seconds pass.
+    fn poll_until(id: &str, done: impl Fn(&[Value]) -> bool) -> Vec<Value> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut events = Vec::new();
+        while !done(&events) && Instant::now() < deadline {
+            let polled: Vec<Value> = serde_json::from_str(&poll(id)).unwrap();
+            events.extend(polled);
+            thread::sleep(POLL_INTERVAL);
+        }
+        events
+    }
+
+    fn count(events: &[Value], kind: &str) -> usize {
+        events.iter().filter(|event| event["type"] == kind).count()
+    }
+
+    #[test]
+    fn test_connect_options() {
+        for options in ["", "null", "[]"] {
+            assert!(connect("options", "ws://127.0.0.1:1", options).is_ok());
+        }
+        let options: ConnectOptions = serde_json::from_str(r#"{"reconnect": 0}"#).unwrap();
+        assert!(!options.reconnect);
+        assert!(connect("options", "not a url", "null").is_err());
+        for options in [
+            r#"{"reconnect_delay": 1e20}"#,
+            r#"{"max_reconnect_delay": 1e20}"#,
+        ] {
+            assert!(connect("options", "ws://127.0.0.1:1", options).is_err());
+        }
+        close("options");
+    }
+
+    #[test]
+    fn test_echo() {
+        let url = serve(|mut socket| {
+            while let Ok(message) = socket.read() {
+                if message.is_text() || message.is_binary() {
+                    socket.send(message).unwrap();
+                }
+            }
+        });
+        connect("echo", &url, r#"{"reconnect": false}"#).unwrap();
+        assert_eq!(send("echo", Message::Text("hello".to_owned())), None);
+        assert_eq!(send("echo", Message::Binary(vec![1, 2, 3])), None);
+
+        let events = poll_until("echo", |events| events.len() >= 3);
+        assert_eq!(events[0]["type"], "open");
+        assert_eq!(events[1]["type"], "message");
+        assert_eq!(events[1]["data"], "hello");
+        assert_eq!(events[2]["type"], "binary");
+        assert_eq!(events[2]["data"], "AQID");
+
+        close("echo");
+        assert_eq!(
+            send("echo", Message::Text("gone".to_owned())),
+            Some("Not connected".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_dropped_messages() {
+        let sent = QUEUE_SIZE + 5;
+        let url = serve(move |mut socket| {
+            for i in 0..sent {
+                socket.send(Message::Text(i.to_string())).unwrap();
+            }
+            while socket.read().is_ok() {}
+        });
+        connect("dropped", &url, r#"{"reconnect": false}"#).unwrap();
+        // Give the connection thread time to fill the queue before anything is polled.
+        thread::sleep(Duration::from_millis(500));
+
+        let events = poll_until("dropped", |events| count(events, "dropped") > 0);
+        let dropped: usize = events
+            .iter()
+            .filter(|event| event["type"] == "dropped")
+            .map(|event| event["data"].as_u64().unwrap() as usize)
+            .sum();
+        assert!(dropped > 0);
+        assert_eq!(count(&events, "message") + dropped, sent);
+        close("dropped");
+    }
+}
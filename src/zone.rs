@@ -0,0 +1,46 @@
+//! Timezone handling shared by the time and log modules.
+use crate::error::{Error, Result};
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::fmt::Write;
+
+/// Timezones accepted from DM: empty or "UTC", "local" for the server's zone, or an IANA name.
+pub enum Zone {
+    Utc,
+    Local,
+    Named(Tz),
+}
+
+impl Zone {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "" | "UTC" | "utc" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            name => name
+                .parse()
+                .map(Self::Named)
+                .map_err(|_| Error::InvalidTimezone(name.to_owned())),
+        }
+    }
+
+    pub fn format(&self, time: &DateTime<Utc>, format: &str) -> Result<String> {
+        fn write_in<Z: TimeZone>(time: DateTime<Z>, format: &str) -> Result<String>
+        where
+            Z::Offset: std::fmt::Display,
+        {
+            if format.is_empty() {
+                return Ok(time.to_rfc3339_opts(SecondsFormat::Millis, true));
+            }
+            // Writing rather than calling `to_string()` turns bad specifiers into an error instead of a panic.
+            let mut out = String::new();
+            write!(out, "{}", time.format(format)).map_err(|_| Error::InvalidTimeFormat)?;
+            Ok(out)
+        }
+
+        match self {
+            Self::Utc => write_in(*time, format),
+            Self::Local => write_in(time.with_timezone(&Local), format),
+            Self::Named(tz) => write_in(time.with_timezone(tz), format),
+        }
+    }
+}
@@ -1,17 +1,53 @@
 use std::process::{Command, Output};
 
+#[cfg(feature = "data")]
+#[test]
+fn data() {
+    run_dm_tests("data");
+}
+
 #[cfg(feature = "git")]
 #[test]
 fn git() {
     run_dm_tests("git");
 }
 
+#[cfg(all(feature = "http", feature = "http_server"))]
+#[test]
+fn http_server() {
+    run_dm_tests("http_server");
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json() {
+    run_dm_tests("json");
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn log() {
+    run_dm_tests("log");
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn time() {
+    run_dm_tests("time");
+}
+
 #[cfg(feature = "toml")]
 #[test]
 fn toml() {
     run_dm_tests("toml");
 }
 
+#[cfg(feature = "unzip")]
+#[test]
+fn unzip() {
+    run_dm_tests("unzip");
+}
+
 #[cfg(feature = "url")]
 #[test]
 fn url() {